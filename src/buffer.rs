@@ -0,0 +1,113 @@
+use ropey::{Rope, RopeSlice};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    ops::Range,
+};
+
+/// The text of the open document, stored as a rope so that line lookups
+/// and edits stay O(log n) on large files.
+///
+/// Positions are given as `(line, column)` pairs where the column counts
+/// chars, matching the cursor in `App`.
+#[derive(Clone)]
+pub struct Buffer {
+    rope: Rope,
+}
+
+impl Buffer {
+    pub fn new() -> Self {
+        Self { rope: Rope::new() }
+    }
+
+    pub fn from_text(text: &str) -> Self {
+        Self {
+            rope: Rope::from_str(text),
+        }
+    }
+
+    pub fn len_lines(&self) -> usize {
+        self.rope.len_lines()
+    }
+
+    pub fn len_chars(&self) -> usize {
+        self.rope.len_chars()
+    }
+
+    /// Line `line_index` without its trailing line break.
+    pub fn line(&self, line_index: usize) -> RopeSlice<'_> {
+        let line = self.rope.line(line_index);
+        let len = line.len_chars();
+        if len > 0 && line.char(len - 1) == '\n' {
+            line.slice(..len - 1)
+        } else {
+            line
+        }
+    }
+
+    pub fn line_string(&self, line_index: usize) -> String {
+        self.line(line_index).to_string()
+    }
+
+    /// Number of chars in the line, not counting the line break.
+    pub fn line_len(&self, line_index: usize) -> usize {
+        if line_index < self.len_lines() {
+            self.line(line_index).len_chars()
+        } else {
+            0
+        }
+    }
+
+    pub fn line_is_empty(&self, line_index: usize) -> bool {
+        self.line_len(line_index) == 0
+    }
+
+    pub fn line_to_char(&self, line_index: usize) -> usize {
+        self.rope.line_to_char(line_index)
+    }
+
+    pub fn char_to_line(&self, char_index: usize) -> usize {
+        self.rope.char_to_line(char_index)
+    }
+
+    /// Absolute char index of a `(line, column)` position.
+    pub fn pos_to_char(&self, line_index: usize, column_index: usize) -> usize {
+        self.line_to_char(line_index) + column_index.min(self.line_len(line_index))
+    }
+
+    /// `(line, column)` position of an absolute char index.
+    pub fn char_to_pos(&self, char_index: usize) -> (usize, usize) {
+        let line_index = self.char_to_line(char_index);
+        (line_index, char_index - self.line_to_char(line_index))
+    }
+
+    pub fn char_at(&self, line_index: usize, column_index: usize) -> Option<char> {
+        if column_index < self.line_len(line_index) {
+            Some(self.line(line_index).char(column_index))
+        } else {
+            None
+        }
+    }
+
+    pub fn insert(&mut self, char_index: usize, text: &str) {
+        self.rope.insert(char_index, text);
+    }
+
+    pub fn remove(&mut self, range: Range<usize>) {
+        self.rope.remove(range);
+    }
+
+    pub fn slice(&self, range: Range<usize>) -> String {
+        self.rope.slice(range).to_string()
+    }
+
+    pub fn chunks(&self) -> impl Iterator<Item = &str> {
+        self.rope.chunks()
+    }
+
+    pub fn write_to(&self, file: &mut File) -> io::Result<()> {
+        let mut writer = BufWriter::new(file);
+        self.rope.write_to(&mut writer)?;
+        writer.flush()
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    env,
+    io::{self, Read, Write},
+    process::{Command, Stdio},
+};
+
+/// The system clipboard behind the `+` register.
+///
+/// Copies are sent to the terminal as an OSC 52 escape sequence, and also
+/// piped to `EDFI_CLIPBOARD_COPY` (e.g. `wl-copy` or
+/// `xclip -selection clipboard`) when it is set, for terminals without
+/// OSC 52 support. Pastes run `EDFI_CLIPBOARD_PASTE` (e.g. `wl-paste -n`),
+/// falling back to the last text copied from edfi. Commands run through
+/// `sh -c`.
+pub struct Clipboard {
+    copy_command: Option<String>,
+    paste_command: Option<String>,
+    last_copy: Option<String>,
+    pending_osc52: Option<String>,
+}
+
+impl Clipboard {
+    pub fn new(copy_command: Option<String>, paste_command: Option<String>) -> Self {
+        Self {
+            copy_command,
+            paste_command,
+            last_copy: None,
+            pending_osc52: None,
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let command = |name| env::var(name).ok().filter(|command| !command.is_empty());
+        Self::new(
+            command("EDFI_CLIPBOARD_COPY"),
+            command("EDFI_CLIPBOARD_PASTE"),
+        )
+    }
+
+    pub fn copy(&mut self, text: &str) -> io::Result<()> {
+        self.last_copy = Some(text.to_string());
+        self.pending_osc52 = Some(format!("\x1b]52;c;{}\x07", base64(text.as_bytes())));
+        let Some(command) = &self.copy_command else {
+            return Ok(());
+        };
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(text.as_bytes())?;
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("`{command}` failed: {status}")));
+        }
+        Ok(())
+    }
+
+    pub fn paste(&self) -> io::Result<String> {
+        let Some(command) = &self.paste_command else {
+            return Ok(self.last_copy.clone().unwrap_or_default());
+        };
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let mut text = String::new();
+        if let Some(mut stdout) = child.stdout.take() {
+            stdout.read_to_string(&mut text)?;
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("`{command}` failed: {status}")));
+        }
+        Ok(text)
+    }
+
+    /// The OSC 52 sequence for the last copy, to be written to the terminal.
+    pub fn take_osc52(&mut self) -> Option<String> {
+        self.pending_osc52.take()
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, byte)| n | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn round_trips_through_commands() {
+        let dir = env::temp_dir().join(format!("edfi-clipboard-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("clipboard");
+        let mut clipboard = Clipboard::new(
+            Some(format!("cat > '{}'", file.display())),
+            Some(format!("cat '{}'", file.display())),
+        );
+        clipboard.copy("héllo\nworld").unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "héllo\nworld");
+        // A fresh clipboard has no last copy, so the text can only come
+        // from the paste command.
+        let clipboard = Clipboard::new(None, Some(format!("cat '{}'", file.display())));
+        assert_eq!(clipboard.paste().unwrap(), "héllo\nworld");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reports_failing_commands() {
+        let mut clipboard = Clipboard::new(Some("exit 1".to_string()), Some("exit 1".to_string()));
+        assert!(clipboard.copy("text").is_err());
+        assert!(clipboard.paste().is_err());
+    }
+
+    #[test]
+    fn pastes_last_copy_without_command() {
+        let mut clipboard = Clipboard::new(None, None);
+        assert_eq!(clipboard.paste().unwrap(), "");
+        clipboard.copy("text").unwrap();
+        assert_eq!(clipboard.paste().unwrap(), "text");
+    }
+
+    #[test]
+    fn encodes_base64() {
+        for (text, encoded) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(base64(text.as_bytes()), encoded);
+        }
+    }
+
+    #[test]
+    fn queues_osc52_sequence() {
+        let mut clipboard = Clipboard::new(None, None);
+        clipboard.copy("hi").unwrap();
+        assert_eq!(
+            clipboard.take_osc52().as_deref(),
+            Some("\x1b]52;c;aGk=\x07")
+        );
+        assert_eq!(clipboard.take_osc52(), None);
+    }
+}
@@ -0,0 +1,263 @@
+use crate::range::{self, LineRange};
+use std::{fs, path::Path};
+
+/// Command names offered by tab completion.
+const COMMANDS: [&str; 10] = [
+    "delete", "e!", "edit", "join", "q!", "quit", "set", "wq", "write", "x",
+];
+
+/// Options known to `:set`.
+const OPTIONS: [&str; 5] = ["bomb", "endofline", "fileencoding", "fileformat", "tabstop"];
+
+/// A parsed `:` command line, run on the lines of the range given before
+/// it, if any.
+pub enum Command {
+    /// `:w [path]`, `:wq` or `:x`. Writing to a path leaves the current
+    /// file name as it is; with a range, only those lines are written.
+    Write { path: Option<String>, quit: bool },
+    /// `:e[!] [path]`; without a path the current file is reloaded.
+    Edit { path: Option<String>, force: bool },
+    /// `:q[!]`
+    Quit { force: bool },
+    /// A range alone, like `:42`, moves to its last line.
+    Goto,
+    /// `:[range]d`
+    Delete,
+    /// `:[range]>` and `:[range]<`
+    Indent { outdent: bool },
+    /// `:[range]j`, joining the lines with single spaces. A single line is
+    /// joined with the next one.
+    Join,
+    /// `:set name[=value]`
+    Set { name: String, value: Option<String> },
+    /// `:[range]s/pattern/replacement/[flags]`, passed on as typed.
+    Substitute(String),
+    /// `:[range]!command` replaces the lines with what they print when
+    /// piped through the shell command; without a range, `:!command`
+    /// inserts its output at the cursor.
+    Filter(String),
+}
+
+pub fn parse(text: &str) -> Result<(Option<LineRange>, Command), String> {
+    let (range, rest) = range::parse(text)?;
+    let rest = rest.trim();
+    if is_substitute(rest) {
+        return Ok((None, Command::Substitute(text.trim().to_string())));
+    }
+    if let Some(command) = rest.strip_prefix('!') {
+        let command = command.trim();
+        if command.is_empty() {
+            return Err("no shell command".to_string());
+        }
+        return Ok((range, Command::Filter(command.to_string())));
+    }
+    let (name, argument) = match rest.split_once(char::is_whitespace) {
+        Some((name, argument)) => (name, Some(argument.trim().to_string())),
+        None => (rest, None),
+    };
+    let argument = argument.filter(|argument| !argument.is_empty());
+    let command = match name {
+        "" if range.is_some() => Command::Goto,
+        "w" | "write" => Command::Write {
+            path: argument,
+            quit: false,
+        },
+        "wq" | "x" => Command::Write {
+            path: argument,
+            quit: true,
+        },
+        "d" | "delete" => Command::Delete,
+        ">" | "<" => Command::Indent {
+            outdent: name == "<",
+        },
+        "j" | "join" => Command::Join,
+        "e" | "edit" | "e!" | "edit!" => Command::Edit {
+            path: argument,
+            force: name.ends_with('!'),
+        },
+        "q" | "quit" | "q!" | "quit!" => Command::Quit {
+            force: name.ends_with('!'),
+        },
+        "set" => {
+            let argument = argument.ok_or("usage: set name[=value]")?;
+            let (name, value) = match argument.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().to_string())),
+                None => (argument.as_str(), None),
+            };
+            let name = match name {
+                "ts" => "tabstop",
+                "ff" => "fileformat",
+                "fenc" => "fileencoding",
+                "eol" => "endofline",
+                name => name,
+            };
+            if !OPTIONS.contains(&name) {
+                return Err(format!("unknown option `{name}`"));
+            }
+            Command::Set {
+                name: name.to_string(),
+                value,
+            }
+        }
+        "" => return Err("no command".to_string()),
+        _ => return Err(format!("unknown command `{name}`")),
+    };
+    if range.is_some()
+        && matches!(
+            command,
+            Command::Edit { .. } | Command::Quit { .. } | Command::Set { .. }
+        )
+    {
+        return Err(format!("`{name}` takes no range"));
+    }
+    Ok((range, command))
+}
+
+/// Whether `text`, after the range, is `s` followed by a delimiter.
+fn is_substitute(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars.next() == Some('s')
+        && chars
+            .next()
+            .is_some_and(|c| !c.is_alphanumeric() && !c.is_whitespace() && c != '\\')
+}
+
+/// The `:` prompt: its text, the history of run commands and the
+/// completions cycled through with `Tab`.
+#[derive(Default)]
+pub struct CommandLine {
+    pub text: String,
+    history: Vec<String>,
+    /// The history entry shown, while browsing with `Up` and `Down`.
+    history_index: Option<usize>,
+    /// What was typed before browsing the history.
+    draft: String,
+    completions: Vec<String>,
+    completion_index: usize,
+}
+
+impl CommandLine {
+    pub fn start(&mut self, text: &str) {
+        self.text = text.to_string();
+        self.history_index = None;
+        self.completions.clear();
+    }
+
+    pub fn push(&mut self, c: char) {
+        self.text.push(c);
+        self.completions.clear();
+    }
+
+    pub fn pop(&mut self) {
+        self.text.pop();
+        self.completions.clear();
+    }
+
+    /// Takes the typed command, adding it to the history.
+    pub fn submit(&mut self) -> String {
+        let text = std::mem::take(&mut self.text);
+        if !text.trim().is_empty() && self.history.last() != Some(&text) {
+            self.history.push(text.clone());
+        }
+        self.history_index = None;
+        text
+    }
+
+    pub fn history_previous(&mut self) {
+        let index = match self.history_index {
+            Some(index) => index.saturating_sub(1),
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = self.text.clone();
+                self.history.len() - 1
+            }
+        };
+        self.history_index = Some(index);
+        self.text = self.history[index].clone();
+        self.completions.clear();
+    }
+
+    pub fn history_next(&mut self) {
+        let Some(index) = self.history_index else {
+            return;
+        };
+        if index + 1 < self.history.len() {
+            self.history_index = Some(index + 1);
+            self.text = self.history[index + 1].clone();
+        } else {
+            self.history_index = None;
+            self.text = std::mem::take(&mut self.draft);
+        }
+        self.completions.clear();
+    }
+
+    /// Completes the word before the end of the line: a command name, an
+    /// option of `:set` or a file path. Pressing `Tab` again moves on to
+    /// the next candidate.
+    pub fn complete(&mut self) {
+        if self.completions.is_empty() {
+            let (prefix, word) = match self.text.rsplit_once(' ') {
+                Some((prefix, word)) => (format!("{prefix} "), word),
+                None => (String::new(), self.text.as_str()),
+            };
+            let candidates = if prefix.is_empty() {
+                COMMANDS
+                    .iter()
+                    .filter(|name| name.starts_with(word))
+                    .map(|name| name.to_string())
+                    .collect()
+            } else if prefix.trim() == "set" {
+                OPTIONS
+                    .iter()
+                    .filter(|name| name.starts_with(word))
+                    .map(|name| format!("{name}="))
+                    .collect()
+            } else {
+                complete_path(word)
+            };
+            if candidates.is_empty() {
+                return;
+            }
+            // The typed text comes last, so cycling can get back to it.
+            self.completions = candidates
+                .into_iter()
+                .map(|candidate| format!("{prefix}{candidate}"))
+                .collect();
+            self.completions.push(self.text.clone());
+            self.completion_index = 0;
+        } else {
+            self.completion_index = (self.completion_index + 1) % self.completions.len();
+        }
+        self.text = self.completions[self.completion_index].clone();
+    }
+}
+
+/// Paths starting with `word`, with a `/` after directories.
+fn complete_path(word: &str) -> Vec<String> {
+    let (dir, name) = match word.rfind('/') {
+        Some(slash) => (&word[..slash + 1], &word[slash + 1..]),
+        None => ("", word),
+    };
+    let read_dir = if dir.is_empty() {
+        Path::new(".")
+    } else {
+        Path::new(dir)
+    };
+    let Ok(entries) = fs::read_dir(read_dir) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<String> = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let file_name = entry.file_name().into_string().ok()?;
+            if !file_name.starts_with(name) || (name.is_empty() && file_name.starts_with('.')) {
+                return None;
+            }
+            let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+            let slash = if is_dir { "/" } else { "" };
+            Some(format!("{dir}{file_name}{slash}"))
+        })
+        .collect();
+    paths.sort();
+    paths
+}
@@ -0,0 +1,187 @@
+use std::fmt::{self, Display};
+
+/// How much of a file is looked at to tell text from binary data.
+const SNIFF_LEN: usize = 8192;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    /// ISO 8859-1: every byte is the char of the same value, so any file
+    /// reads and writes back unchanged.
+    Latin1,
+    Utf16Le,
+    Utf16Be,
+    /// Data that is not text. It is shown with `\xNN` escapes for invalid
+    /// bytes and control chars, and cannot be written back.
+    Binary,
+}
+
+impl Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Encoding::Utf8 => "utf-8",
+            Encoding::Latin1 => "latin1",
+            Encoding::Utf16Le => "utf-16le",
+            Encoding::Utf16Be => "utf-16be",
+            Encoding::Binary => "binary",
+        })
+    }
+}
+
+impl Encoding {
+    /// The encodings `:set fileencoding` converts to.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            "latin1" | "iso-8859-1" => Some(Encoding::Latin1),
+            "utf-16le" => Some(Encoding::Utf16Le),
+            "utf-16be" => Some(Encoding::Utf16Be),
+            _ => None,
+        }
+    }
+
+    fn bom(&self) -> &'static [u8] {
+        match self {
+            Encoding::Utf8 => b"\xef\xbb\xbf",
+            Encoding::Utf16Le => b"\xff\xfe",
+            Encoding::Utf16Be => b"\xfe\xff",
+            Encoding::Latin1 | Encoding::Binary => b"",
+        }
+    }
+
+    /// Works out the encoding of a file's bytes and decodes them, returning
+    /// whether they started with a byte order mark. A BOM decides the
+    /// encoding; otherwise it is UTF-8 when the bytes are valid, UTF-16 when
+    /// every other byte is mostly zero, binary when there are other zero
+    /// bytes or many control chars, and Latin-1 for the rest.
+    pub fn decode(bytes: &[u8]) -> (Self, bool, String) {
+        for encoding in [Encoding::Utf8, Encoding::Utf16Le, Encoding::Utf16Be] {
+            if let Some(rest) = bytes.strip_prefix(encoding.bom()) {
+                if let Some(text) = encoding.decode_as(rest) {
+                    return (encoding, true, text);
+                }
+            }
+        }
+        if let Ok(text) = std::str::from_utf8(bytes) {
+            if !text.contains('\0') {
+                return (Encoding::Utf8, false, text.to_string());
+            }
+        }
+        if let Some(encoding) = utf16_without_bom(bytes) {
+            if let Some(text) = encoding.decode_as(bytes) {
+                return (encoding, false, text);
+            }
+        }
+        let sniff = &bytes[..bytes.len().min(SNIFF_LEN)];
+        let controls = sniff
+            .iter()
+            .filter(|&&byte| byte < 0x20 && !b"\t\n\r\x0c\x1b".contains(&byte))
+            .count();
+        if sniff.contains(&0) || controls * 10 > sniff.len() {
+            return (Encoding::Binary, false, escape(bytes));
+        }
+        (
+            Encoding::Latin1,
+            false,
+            bytes.iter().map(|&byte| byte as char).collect(),
+        )
+    }
+
+    fn decode_as(&self, bytes: &[u8]) -> Option<String> {
+        match self {
+            Encoding::Utf8 => String::from_utf8(bytes.to_vec()).ok(),
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                if !bytes.len().is_multiple_of(2) {
+                    return None;
+                }
+                let units: Vec<u16> = bytes
+                    .chunks_exact(2)
+                    .map(|pair| match self {
+                        Encoding::Utf16Le => u16::from_le_bytes([pair[0], pair[1]]),
+                        _ => u16::from_be_bytes([pair[0], pair[1]]),
+                    })
+                    .collect();
+                String::from_utf16(&units).ok()
+            }
+            Encoding::Latin1 => Some(bytes.iter().map(|&byte| byte as char).collect()),
+            Encoding::Binary => Some(escape(bytes)),
+        }
+    }
+
+    /// Appends `text` in this encoding to `out`. Fails with the first char
+    /// the encoding has no bytes for, which for binary data is any.
+    pub fn encode(&self, text: &str, out: &mut Vec<u8>) -> Result<(), char> {
+        match self {
+            Encoding::Utf8 => out.extend_from_slice(text.as_bytes()),
+            Encoding::Latin1 => {
+                for c in text.chars() {
+                    out.push(u8::try_from(c).map_err(|_| c)?);
+                }
+            }
+            Encoding::Utf16Le => text
+                .encode_utf16()
+                .for_each(|unit| out.extend_from_slice(&unit.to_le_bytes())),
+            Encoding::Utf16Be => text
+                .encode_utf16()
+                .for_each(|unit| out.extend_from_slice(&unit.to_be_bytes())),
+            Encoding::Binary => return Err(text.chars().next().unwrap_or('\0')),
+        }
+        Ok(())
+    }
+
+    /// The byte order mark to start a file with, if `bom` asks for one and
+    /// the encoding has one.
+    pub fn bom_bytes(&self, bom: bool) -> &'static [u8] {
+        if bom {
+            self.bom()
+        } else {
+            b""
+        }
+    }
+}
+
+/// UTF-16 text without a BOM is mostly ASCII, so most of its code units
+/// have a zero high byte.
+fn utf16_without_bom(bytes: &[u8]) -> Option<Encoding> {
+    let sniff = &bytes[..bytes.len().min(SNIFF_LEN) & !1];
+    if sniff.is_empty() || !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+    let units = sniff.len() / 2;
+    let zero_at = |offset: usize| {
+        sniff
+            .chunks_exact(2)
+            .filter(|pair| pair[offset] == 0 && pair[1 - offset] != 0)
+            .count()
+    };
+    if zero_at(1) * 10 >= units * 9 {
+        Some(Encoding::Utf16Le)
+    } else if zero_at(0) * 10 >= units * 9 {
+        Some(Encoding::Utf16Be)
+    } else {
+        None
+    }
+}
+
+/// Valid UTF-8 is kept and everything else becomes a `\xNN` escape, as do
+/// control chars other than tabs and line breaks, so nothing reaches the
+/// terminal unescaped.
+fn escape(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c.is_control() && c != '\n' && c != '\t' {
+                let mut buf = [0; 4];
+                for byte in c.encode_utf8(&mut buf).bytes() {
+                    text.push_str(&format!("\\x{byte:02x}"));
+                }
+            } else {
+                text.push(c);
+            }
+        }
+        for byte in chunk.invalid() {
+            text.push_str(&format!("\\x{byte:02x}"));
+        }
+    }
+    text
+}
@@ -0,0 +1,59 @@
+use std::{
+    io::{Read, Write},
+    process::{Command, Stdio},
+    thread,
+};
+
+/// Runs `command` through `sh -c` with `input` on its standard input and
+/// returns what it printed. A command that fails to start or exits with a
+/// non-zero status is an error, described by the last line it printed to
+/// standard error when there is one.
+pub fn run(command: &str, input: &str) -> Result<String, String> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|error| format!("cannot run sh: {error}"))?;
+    let mut stdin = child.stdin.take();
+    let mut stdout = child.stdout.take();
+    let mut stderr = child.stderr.take();
+
+    // Input is written from another thread, so a command that prints
+    // before it has read everything cannot block on a full pipe.
+    let (output, errors) = thread::scope(|scope| {
+        scope.spawn(move || {
+            if let Some(stdin) = &mut stdin {
+                // A command that does not read its input closes the pipe early.
+                let _ = stdin.write_all(input.as_bytes());
+            }
+        });
+        let errors = scope.spawn(move || {
+            let mut errors = String::new();
+            if let Some(stderr) = &mut stderr {
+                let _ = stderr.read_to_string(&mut errors);
+            }
+            errors
+        });
+        let mut output = Vec::new();
+        if let Some(stdout) = &mut stdout {
+            let _ = stdout.read_to_end(&mut output);
+        }
+        (output, errors.join().unwrap_or_default())
+    });
+
+    let status = child
+        .wait()
+        .map_err(|error| format!("`{command}` failed: {error}"))?;
+    if !status.success() {
+        return Err(
+            match errors.lines().rev().find(|line| !line.trim().is_empty()) {
+                Some(line) => format!("`{command}` failed: {}", line.trim()),
+                None => format!("`{command}` failed: {status}"),
+            },
+        );
+    }
+    String::from_utf8(output).map_err(|_| format!("`{command}` printed invalid UTF-8"))
+}
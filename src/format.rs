@@ -0,0 +1,178 @@
+use crate::encoding::Encoding;
+use std::{
+    borrow::Cow,
+    io::{self, BufWriter, Write},
+};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+/// How a file's text is laid out on disk beyond what the buffer holds. The
+/// buffer always has `\n` line breaks, no line break after the last line
+/// and no byte order mark.
+#[derive(Clone, Copy, PartialEq)]
+pub struct FileFormat {
+    pub encoding: Encoding,
+    pub line_ending: LineEnding,
+    /// Whether the last line ends with a line break.
+    pub final_newline: bool,
+    /// Whether the file starts with a byte order mark.
+    pub bom: bool,
+}
+
+impl Default for FileFormat {
+    /// The format of new files.
+    fn default() -> Self {
+        Self {
+            encoding: Encoding::Utf8,
+            line_ending: LineEnding::Lf,
+            final_newline: true,
+            bom: false,
+        }
+    }
+}
+
+impl FileFormat {
+    /// Detects the format of a file's contents and returns it with the text
+    /// as the buffer holds it. Files mixing line endings take the one most
+    /// lines use, and are written back with that one only. An empty file
+    /// has no line break to keep, unlike one holding just a line break.
+    pub fn decode(bytes: &[u8]) -> (Self, String) {
+        if bytes.is_empty() {
+            let format = Self {
+                final_newline: false,
+                ..Self::default()
+            };
+            return (format, String::new());
+        }
+        let (encoding, bom, content) = Encoding::decode(bytes);
+        let breaks = content.matches('\n').count();
+        let crlf_breaks = content.matches("\r\n").count();
+        let line_ending = if crlf_breaks * 2 > breaks {
+            LineEnding::Crlf
+        } else {
+            LineEnding::Lf
+        };
+        let (final_newline, text) = match content.strip_suffix('\n') {
+            Some(text) => (true, text.strip_suffix('\r').unwrap_or(text)),
+            None => (false, content.as_str()),
+        };
+        let format = Self {
+            encoding,
+            line_ending,
+            final_newline,
+            bom,
+        };
+        (format, text.replace("\r\n", "\n"))
+    }
+
+    /// Writes buffer text given as `chunks` in this format. Binary files and
+    /// text the encoding cannot represent are refused.
+    pub fn write<'a>(
+        &self,
+        chunks: impl Iterator<Item = &'a str>,
+        out: impl Write,
+    ) -> io::Result<()> {
+        if self.encoding == Encoding::Binary {
+            return Err(io::Error::other(
+                "binary file shown with escapes, it cannot be saved",
+            ));
+        }
+        let ending = match self.line_ending {
+            LineEnding::Lf => "\n",
+            LineEnding::Crlf => "\r\n",
+        };
+        let mut writer = BufWriter::new(out);
+        let mut bytes = self.encoding.bom_bytes(self.bom).to_vec();
+        for chunk in chunks {
+            let chunk: Cow<str> = match self.line_ending {
+                LineEnding::Lf => chunk.into(),
+                LineEnding::Crlf => chunk.replace('\n', ending).into(),
+            };
+            self.encode(&chunk, &mut bytes)?;
+            writer.write_all(&bytes)?;
+            bytes.clear();
+        }
+        if self.final_newline {
+            self.encode(ending, &mut bytes)?;
+        }
+        writer.write_all(&bytes)?;
+        writer.flush()
+    }
+
+    fn encode(&self, text: &str, out: &mut Vec<u8>) -> io::Result<()> {
+        self.encoding
+            .encode(text, out)
+            .map_err(|c| io::Error::other(format!("{c:?} cannot be written in {}", self.encoding)))
+    }
+
+    /// A short description for the status bar, empty for the format of new
+    /// files.
+    pub fn label(&self) -> String {
+        let mut parts = Vec::new();
+        if self.encoding != Encoding::Utf8 {
+            parts.push(self.encoding.to_string());
+        }
+        if self.line_ending == LineEnding::Crlf {
+            parts.push("crlf".to_string());
+        }
+        if !self.final_newline {
+            parts.push("noeol".to_string());
+        }
+        if self.bom {
+            parts.push("bom".to_string());
+        }
+        parts.join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(bytes: &[u8]) -> Vec<u8> {
+        let (format, text) = FileFormat::decode(bytes);
+        let mut out = Vec::new();
+        format.write([text.as_str()].into_iter(), &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn writes_files_back_unchanged() {
+        for bytes in [
+            &b""[..],
+            b"\n",
+            b"\r\n",
+            b"one\ntwo\n",
+            b"one\r\ntwo\r\n",
+            b"one\ntwo",
+            b"one\r\ntwo",
+            b"\xef\xbb\xbfone\ntwo\n",
+            b"\xff\xfeo\0n\0e\0\r\0\n\0",
+        ] {
+            assert_eq!(
+                round_trip(bytes),
+                bytes,
+                "{:?}",
+                String::from_utf8_lossy(bytes)
+            );
+        }
+    }
+
+    #[test]
+    fn detects_format() {
+        let (format, text) = FileFormat::decode(b"\xef\xbb\xbfa\r\nb");
+        assert!(format.bom);
+        assert!(format.line_ending == LineEnding::Crlf);
+        assert!(!format.final_newline);
+        assert_eq!(text, "a\nb");
+        let (format, text) = FileFormat::decode(b"\n");
+        assert!(format.final_newline);
+        assert_eq!(text, "");
+        let (format, _) = FileFormat::decode(b"");
+        assert!(!format.final_newline);
+    }
+}
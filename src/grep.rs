@@ -0,0 +1,285 @@
+use regex::Regex;
+use std::{
+    fs::{self, read_to_string},
+    path::{Path, PathBuf},
+};
+
+/// Searching stops after this many hits, so a pattern that matches
+/// everywhere does not stall the editor.
+const MAX_HITS: usize = 10_000;
+
+/// A match in some file, at char `column` of `line`.
+#[derive(Clone)]
+pub struct Hit {
+    pub path: String,
+    pub line: usize,
+    pub column: usize,
+    pub text: String,
+}
+
+/// One line of a `.gitignore` file.
+struct Rule {
+    pattern: Vec<char>,
+    negate: bool,
+    dir_only: bool,
+    /// Patterns with a `/` before the end match the path relative to the
+    /// `.gitignore`, the others match the file name at any depth.
+    anchored: bool,
+}
+
+/// The rules of the `.gitignore` in `base`.
+struct Gitignore {
+    base: PathBuf,
+    rules: Vec<Rule>,
+}
+
+impl Gitignore {
+    fn load(base: &Path) -> Option<Self> {
+        let text = read_to_string(base.join(".gitignore")).ok()?;
+        Some(Self::parse(base, &text))
+    }
+
+    fn parse(base: &Path, text: &str) -> Self {
+        let rules = text
+            .lines()
+            .map(str::trim_end)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let (negate, line) = match line.strip_prefix('!') {
+                    Some(rest) => (true, rest),
+                    None => (false, line),
+                };
+                let (dir_only, line) = match line.strip_suffix('/') {
+                    Some(rest) => (true, rest),
+                    None => (false, line),
+                };
+                let anchored = line.contains('/');
+                Rule {
+                    pattern: line.trim_start_matches('/').chars().collect(),
+                    negate,
+                    dir_only,
+                    anchored,
+                }
+            })
+            .collect();
+        Self {
+            base: base.to_path_buf(),
+            rules,
+        }
+    }
+
+    /// Whether the last rule matching `path` ignores it, if any rule does.
+    fn matches(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let relative = path.strip_prefix(&self.base).ok()?.to_string_lossy();
+        let relative: Vec<char> = relative.chars().collect();
+        let name: Vec<char> = path.file_name()?.to_string_lossy().chars().collect();
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| {
+                (is_dir || !rule.dir_only)
+                    && if rule.anchored {
+                        glob_match(&rule.pattern, &relative)
+                    } else {
+                        glob_match(&rule.pattern, &name)
+                    }
+            })
+            .map(|rule| !rule.negate)
+    }
+}
+
+/// Matches gitignore globs: `*` and `?` stop at `/`, `**` does not, and
+/// `[...]` is a character class.
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        ['*', '*', '/', rest @ ..] => (0..=text.len())
+            .filter(|&skip| skip == 0 || text[skip - 1] == '/')
+            .any(|skip| glob_match(rest, &text[skip..])),
+        ['*', '*', rest @ ..] => (0..=text.len()).any(|skip| glob_match(rest, &text[skip..])),
+        ['*', rest @ ..] => (0..=text.len())
+            .take_while(|&skip| skip == 0 || text[skip - 1] != '/')
+            .any(|skip| glob_match(rest, &text[skip..])),
+        ['?', rest @ ..] => {
+            matches!(text.first(), Some(c) if *c != '/') && glob_match(rest, &text[1..])
+        }
+        ['[', class @ ..] => {
+            let Some(end) = class.iter().skip(1).position(|c| *c == ']') else {
+                return text.first() == Some(&'[') && glob_match(class, &text[1..]);
+            };
+            let (class, rest) = (&class[..end + 1], &class[end + 2..]);
+            let (negate, class) = match class {
+                ['!' | '^', class @ ..] => (true, class),
+                _ => (false, class),
+            };
+            let Some(c) = text.first() else {
+                return false;
+            };
+            let mut found = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == '-' {
+                    found |= (class[i]..=class[i + 2]).contains(c);
+                    i += 3;
+                } else {
+                    found |= class[i] == *c;
+                    i += 1;
+                }
+            }
+            found != negate && glob_match(rest, &text[1..])
+        }
+        [c, rest @ ..] => text.first() == Some(c) && glob_match(rest, &text[1..]),
+    }
+}
+
+/// Searches every text file under `root`, skipping `.git` and whatever the
+/// `.gitignore` files on the way exclude. Files that are not UTF-8 are
+/// taken to be binary and skipped.
+pub fn grep(root: &Path, regex: &Regex) -> Vec<Hit> {
+    let mut hits = Vec::new();
+    let mut ignores = Vec::new();
+    walk(root, root, regex, &mut ignores, &mut hits);
+    hits
+}
+
+fn walk(root: &Path, dir: &Path, regex: &Regex, ignores: &mut Vec<Gitignore>, hits: &mut Vec<Hit>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut entries: Vec<_> = entries.filter_map(Result::ok).collect();
+    entries.sort_by_key(|entry| entry.file_name());
+    let loaded = match Gitignore::load(dir) {
+        Some(gitignore) => {
+            ignores.push(gitignore);
+            true
+        }
+        None => false,
+    };
+    for entry in entries {
+        if hits.len() >= MAX_HITS {
+            break;
+        }
+        let path = entry.path();
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        let is_dir = file_type.is_dir();
+        if entry.file_name() == ".git" || is_ignored(ignores, &path, is_dir) {
+            continue;
+        }
+        if is_dir {
+            walk(root, &path, regex, ignores, hits);
+        } else if file_type.is_file() {
+            search_file(root, &path, regex, hits);
+        }
+    }
+    if loaded {
+        ignores.pop();
+    }
+}
+
+/// Deeper `.gitignore` files come later and override the ones above.
+fn is_ignored(ignores: &[Gitignore], path: &Path, is_dir: bool) -> bool {
+    ignores
+        .iter()
+        .rev()
+        .find_map(|gitignore| gitignore.matches(path, is_dir))
+        .unwrap_or(false)
+}
+
+fn search_file(root: &Path, path: &Path, regex: &Regex, hits: &mut Vec<Hit>) {
+    let Ok(text) = read_to_string(path) else {
+        return;
+    };
+    let display = path.strip_prefix(root).unwrap_or(path);
+    for (line_index, line) in text.lines().enumerate() {
+        if let Some(found) = regex.find(line) {
+            hits.push(Hit {
+                path: display.to_string_lossy().into_owned(),
+                line: line_index,
+                column: line[..found.start()].chars().count(),
+                text: line.trim().to_string(),
+            });
+            if hits.len() >= MAX_HITS {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ignored(gitignore: &str, path: &str, is_dir: bool) -> bool {
+        let gitignore = Gitignore::parse(Path::new("/repo"), gitignore);
+        is_ignored(&[gitignore], &Path::new("/repo").join(path), is_dir)
+    }
+
+    #[test]
+    fn matches_names_at_any_depth() {
+        assert!(ignored("*.log", "debug.log", false));
+        assert!(ignored("*.log", "src/deep/debug.log", false));
+        assert!(!ignored("*.log", "debug.log.txt", false));
+        assert!(!ignored("*.log", "log", false));
+    }
+
+    #[test]
+    fn anchors_patterns_with_a_slash() {
+        assert!(ignored("/target", "target", true));
+        assert!(ignored("/target", "target", false));
+        assert!(!ignored("/target", "src/target", true));
+    }
+
+    #[test]
+    fn matches_directories_only_with_a_trailing_slash() {
+        assert!(ignored("build/", "build", true));
+        assert!(ignored("build/", "src/build", true));
+        assert!(!ignored("build/", "build", false));
+    }
+
+    #[test]
+    fn matches_any_directories_with_double_star() {
+        assert!(ignored("a/**/b", "a/b", false));
+        assert!(ignored("a/**/b", "a/x/b", false));
+        assert!(ignored("a/**/b", "a/x/y/b", false));
+        assert!(!ignored("a/**/b", "a/xb", false));
+        assert!(!ignored("a/**/b", "c/a/x/b", false));
+    }
+
+    #[test]
+    fn negates_earlier_rules() {
+        let gitignore = "*.log\n!keep.log";
+        assert!(ignored(gitignore, "debug.log", false));
+        assert!(!ignored(gitignore, "keep.log", false));
+        assert!(!ignored(gitignore, "src/keep.log", false));
+    }
+
+    #[test]
+    fn matches_character_classes() {
+        assert!(ignored("file[a-c].txt", "fileb.txt", false));
+        assert!(!ignored("file[a-c].txt", "filed.txt", false));
+        assert!(ignored("file[!a-c].txt", "filed.txt", false));
+        assert!(ignored("file[xyz].txt", "filey.txt", false));
+    }
+
+    #[test]
+    fn skips_comments_and_blank_lines() {
+        assert!(!ignored("# *.log\n\n", "debug.log", false));
+    }
+
+    #[test]
+    fn nested_gitignore_overrides_parent() {
+        let ignores = [
+            Gitignore::parse(Path::new("/repo"), "*.log\nout/"),
+            Gitignore::parse(Path::new("/repo/sub"), "!keep.log\n*.tmp"),
+        ];
+        let path = |path: &str| Path::new("/repo").join(path);
+        assert!(is_ignored(&ignores, &path("debug.log"), false));
+        assert!(is_ignored(&ignores, &path("sub/debug.log"), false));
+        assert!(!is_ignored(&ignores, &path("sub/keep.log"), false));
+        assert!(is_ignored(&ignores, &path("sub/out"), true));
+        assert!(is_ignored(&ignores, &path("sub/a.tmp"), false));
+        assert!(!is_ignored(&ignores[..1], &path("a.tmp"), false));
+    }
+}
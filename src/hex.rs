@@ -0,0 +1,110 @@
+use std::{fs, io};
+
+pub const BYTES_PER_ROW: usize = 16;
+
+/// The raw bytes of a file being edited in hex mode. Editing overwrites
+/// nibbles in place, so the file keeps its size.
+pub struct HexEditor {
+    pub bytes: Vec<u8>,
+    /// The bytes as last loaded or saved, to show which ones changed.
+    pub saved: Vec<u8>,
+    /// Index of the byte under the cursor.
+    pub cursor: usize,
+    /// Whether the cursor is on the low nibble, the second hex digit.
+    pub low_nibble: bool,
+    /// Overwritten bytes with their old values, for undo.
+    undo: Vec<(usize, u8)>,
+}
+
+impl HexEditor {
+    pub fn load(path: &str) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        Ok(Self {
+            saved: bytes.clone(),
+            bytes,
+            cursor: 0,
+            low_nibble: false,
+            undo: Vec::new(),
+        })
+    }
+
+    pub fn is_modified(&self) -> bool {
+        self.bytes != self.saved
+    }
+
+    pub fn len_rows(&self) -> usize {
+        self.bytes.len().div_ceil(BYTES_PER_ROW).max(1)
+    }
+
+    pub fn cursor_row(&self) -> usize {
+        self.cursor / BYTES_PER_ROW
+    }
+
+    /// Moves the cursor to the byte `delta` bytes away, staying in the file.
+    pub fn move_by(&mut self, delta: isize) {
+        let last = self.bytes.len().saturating_sub(1);
+        self.cursor = self.cursor.saturating_add_signed(delta).min(last);
+        self.low_nibble = false;
+    }
+
+    /// Moves one nibble left or right, across bytes.
+    pub fn move_nibble(&mut self, right: bool) {
+        match (right, self.low_nibble) {
+            (true, false) if !self.bytes.is_empty() => self.low_nibble = true,
+            (true, true) if self.cursor + 1 < self.bytes.len() => {
+                self.cursor += 1;
+                self.low_nibble = false;
+            }
+            (false, true) => self.low_nibble = false,
+            (false, false) if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.low_nibble = true;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn row_start(&mut self) {
+        self.cursor -= self.cursor % BYTES_PER_ROW;
+        self.low_nibble = false;
+    }
+
+    pub fn row_end(&mut self) {
+        let end = self.cursor - self.cursor % BYTES_PER_ROW + BYTES_PER_ROW - 1;
+        self.cursor = end.min(self.bytes.len().saturating_sub(1));
+        self.low_nibble = false;
+    }
+
+    /// Overwrites the nibble under the cursor with `digit` and moves on to
+    /// the next one.
+    pub fn set_nibble(&mut self, digit: u8) {
+        let Some(byte) = self.bytes.get_mut(self.cursor) else {
+            return;
+        };
+        self.undo.push((self.cursor, *byte));
+        *byte = if self.low_nibble {
+            (*byte & 0xf0) | digit
+        } else {
+            (*byte & 0x0f) | (digit << 4)
+        };
+        self.move_nibble(true);
+    }
+
+    /// Restores the byte changed last and moves the cursor to it.
+    pub fn undo(&mut self) -> bool {
+        let Some((index, byte)) = self.undo.pop() else {
+            return false;
+        };
+        self.bytes[index] = byte;
+        self.cursor = index;
+        self.low_nibble = false;
+        true
+    }
+}
+
+/// The screen column of the hex digits of byte `index` in its row: an
+/// eight digit offset, then the bytes in two groups of eight.
+pub fn hex_column(index: usize) -> usize {
+    let column = index % BYTES_PER_ROW;
+    10 + column * 3 + column / 8
+}
@@ -0,0 +1,389 @@
+use crate::buffer::Buffer;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Cursor position as `(line, column)`.
+pub type Cursor = (usize, usize);
+
+/// A single change to the buffer, addressed by absolute char index.
+#[derive(Clone)]
+pub enum Edit {
+    Insert { at: usize, text: String },
+    Delete { at: usize, text: String },
+}
+
+impl Edit {
+    fn apply(&self, buffer: &mut Buffer) {
+        match self {
+            Edit::Insert { at, text } => buffer.insert(*at, text),
+            Edit::Delete { at, text } => buffer.remove(*at..*at + text.chars().count()),
+        }
+    }
+
+    fn revert(&self, buffer: &mut Buffer) {
+        match self {
+            Edit::Insert { at, text } => buffer.remove(*at..*at + text.chars().count()),
+            Edit::Delete { at, text } => buffer.insert(*at, text),
+        }
+    }
+}
+
+/// The edits made by one command, with the cursor before and after it.
+#[derive(Clone)]
+struct Change {
+    edits: Vec<Edit>,
+    cursor_before: Cursor,
+    cursor_after: Cursor,
+}
+
+/// A state of the document, reached from `parent` by applying `change`.
+struct Node {
+    change: Change,
+    parent: usize,
+    /// The child `redo` follows: the branch that was visited last.
+    redo_child: Option<usize>,
+    time: SystemTime,
+}
+
+/// A state of the undo tree as listed in the undo tree view.
+pub struct Entry {
+    pub seq: usize,
+    pub parent: Option<usize>,
+    pub edits: usize,
+    pub time: SystemTime,
+    pub is_tip: bool,
+}
+
+/// Undo tree of recorded edits. Editing after an undo starts a new branch
+/// instead of discarding the undone one. Only the changed text is kept, so
+/// memory grows with what was edited rather than with the file size.
+///
+/// Node 0 is the text as it was loaded. Nodes are numbered in the order
+/// they were created, which is the order `earlier` and `later` walk.
+pub struct History {
+    nodes: Vec<Node>,
+    current: usize,
+    pending: Option<Change>,
+    last_edit: Option<Instant>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            nodes: vec![Node {
+                change: Change {
+                    edits: Vec::new(),
+                    cursor_before: (0, 0),
+                    cursor_after: (0, 0),
+                },
+                parent: 0,
+                redo_child: None,
+                time: SystemTime::now(),
+            }],
+            current: 0,
+            pending: None,
+            last_edit: None,
+        }
+    }
+}
+
+impl History {
+    /// Starts collecting edits for a new undo step.
+    pub fn begin(&mut self, cursor: Cursor) {
+        self.pending = Some(Change {
+            edits: Vec::new(),
+            cursor_before: cursor,
+            cursor_after: cursor,
+        });
+        self.last_edit = Some(Instant::now());
+    }
+
+    pub fn record(&mut self, edit: Edit) {
+        if let Some(change) = &mut self.pending {
+            change.edits.push(edit);
+            self.last_edit = Some(Instant::now());
+        }
+    }
+
+    /// Whether the open undo step has edits not yet committed.
+    pub fn has_pending_edits(&self) -> bool {
+        self.pending
+            .as_ref()
+            .is_some_and(|change| !change.edits.is_empty())
+    }
+
+    /// Time since the open undo step was last extended, if one is open.
+    pub fn idle_for(&self) -> Option<Duration> {
+        self.last_edit.map(|last_edit| last_edit.elapsed())
+    }
+
+    /// Closes the current undo step as a new child of the current state.
+    /// Steps that changed nothing are dropped.
+    pub fn commit(&mut self, cursor: Cursor) {
+        self.last_edit = None;
+        if let Some(mut change) = self.pending.take() {
+            if !change.edits.is_empty() {
+                change.cursor_after = cursor;
+                let seq = self.nodes.len();
+                self.nodes.push(Node {
+                    change,
+                    parent: self.current,
+                    redo_child: None,
+                    time: SystemTime::now(),
+                });
+                self.nodes[self.current].redo_child = Some(seq);
+                self.current = seq;
+            }
+        }
+    }
+
+    pub fn undo(&mut self, buffer: &mut Buffer) -> Option<Cursor> {
+        if self.current == 0 {
+            return None;
+        }
+        let node = &self.nodes[self.current];
+        for edit in node.change.edits.iter().rev() {
+            edit.revert(buffer);
+        }
+        self.current = node.parent;
+        Some(node.change.cursor_before)
+    }
+
+    pub fn redo(&mut self, buffer: &mut Buffer) -> Option<Cursor> {
+        let seq = self.nodes[self.current].redo_child?;
+        let node = &self.nodes[seq];
+        for edit in &node.change.edits {
+            edit.apply(buffer);
+        }
+        self.current = seq;
+        Some(node.change.cursor_after)
+    }
+
+    /// Moves to the state created just before the current one.
+    pub fn earlier(&mut self, buffer: &mut Buffer) -> Option<Cursor> {
+        self.goto(self.current.checked_sub(1)?, buffer)
+    }
+
+    /// Moves to the state created just after the current one.
+    pub fn later(&mut self, buffer: &mut Buffer) -> Option<Cursor> {
+        self.goto(self.current + 1, buffer)
+    }
+
+    /// Moves to any state in the tree by undoing up to the common ancestor
+    /// and redoing down the target's branch.
+    pub fn goto(&mut self, seq: usize, buffer: &mut Buffer) -> Option<Cursor> {
+        if seq >= self.nodes.len() || seq == self.current {
+            return None;
+        }
+        let mut path = Vec::new();
+        let mut ancestor = seq;
+        while !self.is_ancestor(ancestor, self.current) {
+            path.push(ancestor);
+            ancestor = self.nodes[ancestor].parent;
+        }
+        let mut cursor = None;
+        while self.current != ancestor {
+            cursor = self.undo(buffer);
+        }
+        for seq in path.into_iter().rev() {
+            self.nodes[self.current].redo_child = Some(seq);
+            cursor = self.redo(buffer);
+        }
+        cursor
+    }
+
+    fn is_ancestor(&self, ancestor: usize, mut seq: usize) -> bool {
+        while seq != ancestor {
+            if seq == 0 {
+                return false;
+            }
+            seq = self.nodes[seq].parent;
+        }
+        true
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = Entry> + '_ {
+        self.nodes.iter().enumerate().map(|(seq, node)| Entry {
+            seq,
+            parent: (seq != 0).then_some(node.parent),
+            edits: node.change.edits.len(),
+            time: node.time,
+            is_tip: node.redo_child.is_none(),
+        })
+    }
+}
+
+// Undo file encoding: a `nodes current` line, then for every node a line
+// `parent redo_child time line col line col edits`, with `-` for a missing
+// redo child and the time in seconds since the epoch. Each edit is
+// `i|d at len` followed by `len` bytes of text and a newline.
+impl History {
+    pub fn encode(&self) -> String {
+        let mut out = format!("{} {}\n", self.nodes.len(), self.current);
+        for node in &self.nodes {
+            let (line_before, col_before) = node.change.cursor_before;
+            let (line_after, col_after) = node.change.cursor_after;
+            let redo_child = node
+                .redo_child
+                .map_or("-".to_string(), |seq| seq.to_string());
+            let time = node
+                .time
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs());
+            out.push_str(&format!(
+                "{} {redo_child} {time} {line_before} {col_before} {line_after} {col_after} {}\n",
+                node.parent,
+                node.change.edits.len()
+            ));
+            for edit in &node.change.edits {
+                let (kind, at, text) = match edit {
+                    Edit::Insert { at, text } => ('i', at, text),
+                    Edit::Delete { at, text } => ('d', at, text),
+                };
+                out.push_str(&format!("{kind} {at} {}\n{text}\n", text.len()));
+            }
+        }
+        out
+    }
+
+    pub fn decode(text: &str) -> Option<Self> {
+        let mut rest = text;
+        let (count, current) = next_line(&mut rest)?.split_once(' ')?;
+        let count: usize = count.parse().ok()?;
+        let current: usize = current.parse().ok()?;
+        let mut nodes = Vec::new();
+        for seq in 0..count {
+            let fields: Vec<&str> = next_line(&mut rest)?.split(' ').collect();
+            let [parent, redo_child, time, numbers @ ..] = &fields[..] else {
+                return None;
+            };
+            let parent: usize = parent.parse().ok()?;
+            let redo_child = match *redo_child {
+                "-" => None,
+                seq => Some(seq.parse().ok()?),
+            };
+            let time = UNIX_EPOCH + Duration::from_secs(time.parse().ok()?);
+            let numbers: Vec<usize> = numbers
+                .iter()
+                .map(|field| field.parse().ok())
+                .collect::<Option<_>>()?;
+            let [line_before, col_before, line_after, col_after, edit_count] = numbers[..] else {
+                return None;
+            };
+            // Parents always come before their children.
+            if (seq > 0 && parent >= seq) || redo_child.is_some_and(|child| child >= count) {
+                return None;
+            }
+            let mut edits = Vec::new();
+            for _ in 0..edit_count {
+                let header = next_line(&mut rest)?;
+                let mut fields = header.split(' ');
+                let kind = fields.next()?;
+                let at: usize = fields.next()?.parse().ok()?;
+                let len: usize = fields.next()?.parse().ok()?;
+                let text = rest.get(..len)?.to_string();
+                rest = rest.get(len..)?.strip_prefix('\n')?;
+                edits.push(match kind {
+                    "i" => Edit::Insert { at, text },
+                    "d" => Edit::Delete { at, text },
+                    _ => return None,
+                });
+            }
+            nodes.push(Node {
+                change: Change {
+                    edits,
+                    cursor_before: (line_before, col_before),
+                    cursor_after: (line_after, col_after),
+                },
+                parent,
+                redo_child,
+                time,
+            });
+        }
+        if current >= nodes.len() {
+            return None;
+        }
+        Some(Self {
+            nodes,
+            current,
+            pending: None,
+            last_edit: None,
+        })
+    }
+}
+
+fn next_line<'a>(rest: &mut &'a str) -> Option<&'a str> {
+    let (line, tail) = rest.split_once('\n')?;
+    *rest = tail;
+    Some(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(buffer: &Buffer) -> String {
+        buffer.slice(0..buffer.len_chars())
+    }
+
+    fn change(history: &mut History, buffer: &mut Buffer, edit: Edit, cursor: Cursor) {
+        history.begin((0, 0));
+        edit.apply(buffer);
+        history.record(edit);
+        history.commit(cursor);
+    }
+
+    /// `héllo`, with one branch appending a line and a later one deleting
+    /// the first two chars.
+    fn branched() -> (History, Buffer) {
+        let mut history = History::default();
+        let mut buffer = Buffer::from_text("héllo");
+        let insert = Edit::Insert {
+            at: 5,
+            text: " wörld\n✓".to_string(),
+        };
+        change(&mut history, &mut buffer, insert, (1, 1));
+        history.undo(&mut buffer);
+        let delete = Edit::Delete {
+            at: 0,
+            text: "hé".to_string(),
+        };
+        change(&mut history, &mut buffer, delete, (0, 0));
+        (history, buffer)
+    }
+
+    #[test]
+    fn round_trips_through_encoding() {
+        let (history, mut buffer) = branched();
+        let mut history = History::decode(&history.encode()).unwrap();
+        assert_eq!(history.current(), 2);
+        assert_eq!(history.len(), 3);
+        assert_eq!(text(&buffer), "llo");
+        assert_eq!(history.undo(&mut buffer), Some((0, 0)));
+        assert_eq!(text(&buffer), "héllo");
+        assert_eq!(history.undo(&mut buffer), None);
+        assert_eq!(history.redo(&mut buffer), Some((0, 0)));
+        assert_eq!(text(&buffer), "llo");
+        assert_eq!(history.goto(1, &mut buffer), Some((1, 1)));
+        assert_eq!(text(&buffer), "héllo wörld\n✓");
+        assert_eq!(history.current(), 1);
+        assert_eq!(history.undo(&mut buffer), Some((0, 0)));
+        assert_eq!(history.redo(&mut buffer), Some((1, 1)));
+        assert_eq!(text(&buffer), "héllo wörld\n✓");
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let encoded = branched().0.encode();
+        for (end, _) in encoded.char_indices() {
+            assert!(History::decode(&encoded[..end]).is_none(), "{end}");
+        }
+    }
+}
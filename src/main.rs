@@ -1,7 +1,7 @@
-use color_eyre::Result;
+use color_eyre::{eyre::WrapErr, Result, Section};
 use ratatui::{
-    crossterm::event::{self, Event, KeyCode, KeyEventKind},
-    layout::{Constraint, Layout, Position},
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout, Position, Rect},
     style::{Color, Style, Stylize},
     text::{Line, Span, Text},
     widgets::Paragraph,
@@ -9,42 +9,120 @@ use ratatui::{
 };
 use std::{
     env,
-    fs::{read_to_string, write, File},
+    fs::{self, File},
+    io::{self, Write},
+    ops::{Range, RangeInclusive},
     path::Path,
+    time::{Duration, Instant, SystemTime},
 };
 
+mod buffer;
+mod clipboard;
+mod command;
+mod encoding;
+mod filter;
+mod format;
+mod grep;
+mod hex;
+mod history;
+mod range;
+mod registers;
 mod rust;
+mod save;
+mod search;
+mod substitute;
+mod swap;
+mod terminal;
+mod undofile;
+use buffer::Buffer;
+use clipboard::Clipboard;
+use command::{Command, CommandLine};
+use encoding::Encoding;
+use format::{FileFormat, LineEnding};
+use grep::Hit;
+use hex::{HexEditor, BYTES_PER_ROW};
+use history::{Cursor, Edit, Entry, History};
+use registers::{Register, Registers};
 use rust::rust_tokens;
+use search::{Match, SearchOptions};
+use substitute::Confirm;
+use swap::{Recovery, Swap};
+use terminal::TerminalGuard;
+
+/// Typing that pauses for longer than this starts a new undo step.
+const UNDO_GROUP_PAUSE: Duration = Duration::from_secs(1);
+/// How often unsaved changes are written to the recovery file.
+const SWAP_INTERVAL: Duration = Duration::from_secs(4);
 
 fn main() -> Result<()> {
+    color_eyre::install()?;
     let args: Vec<String> = env::args().collect();
     let save_path;
-    let mut file_text: Vec<String> = Vec::new();
+    let mut file_text = String::new();
+    let mut file_format = FileFormat::default();
     let mut file_opened: bool = false;
+    // Persistent undo is opt-in, as it writes file contents to the state directory.
+    let undo_file = env::var_os("EDFI_UNDOFILE").is_some_and(|value| value == "1");
+    let swap_file = env::var_os("EDFI_SWAPFILE").is_none_or(|value| value != "0");
     if args.len() > 1 {
         let file_path = &args[1];
         save_path = file_path.to_string();
         if !file_path.is_empty() {
             if Path::new(file_path).exists() {
-                let content = read_to_string(file_path).expect("Error: reading file error: ");
-                let mut lines: Vec<String> = content.lines().map(|line| line.to_string()).collect();
-                if lines.is_empty() {
-                    lines.push(" ".to_string());
-                }
-                file_text = lines;
+                (file_text, file_format) =
+                    read_file(file_path).wrap_err_with(|| format!("cannot read {file_path}"))?;
                 file_opened = true;
             } else {
-                File::create(&save_path).expect("Error: New file creating error.");
+                File::create(&save_path).wrap_err_with(|| format!("cannot create {save_path}"))?;
             }
         }
     } else {
         save_path = "new_file".to_string();
     }
-    color_eyre::install()?;
-    let terminal = ratatui::init();
-    let app_result = App::new(save_path, file_text, file_opened).run(terminal);
-    ratatui::restore();
-    app_result
+    // Asked before the terminal switches to the editor, on the plain console.
+    let mut recovery = None;
+    if swap_file {
+        match swap::prompt(&save_path, &file_text)? {
+            Recovery::Recover(swap) => recovery = Some(swap),
+            Recovery::Ignore => {}
+            Recovery::Quit => return Ok(()),
+        }
+    }
+    // Dropped after `run` returns and before an error report is printed.
+    let (_guard, terminal) = TerminalGuard::init()?;
+    let app = App::new(
+        save_path,
+        file_text,
+        file_format,
+        file_opened,
+        undo_file,
+        swap_file,
+    );
+    app.run(terminal, recovery)
+}
+
+/// Reads a file as the editor holds it, with the format to write it back in.
+/// Files that are not text open with their invalid bytes escaped.
+fn read_file(path: &str) -> io::Result<(String, FileFormat)> {
+    let (format, text) = FileFormat::decode(&fs::read(path)?);
+    Ok((text, format))
+}
+
+fn on_off(value: bool) -> &'static str {
+    if value {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+/// Command output as buffer text: `\n` line breaks, without the final one.
+fn command_output_text(output: &str) -> String {
+    let output = output.replace("\r\n", "\n");
+    match output.strip_suffix('\n') {
+        Some(output) => output.to_string(),
+        None => output,
+    }
 }
 
 fn syntax_highln(line: String) -> Line<'static> {
@@ -102,44 +180,160 @@ fn syntax_highln(line: String) -> Line<'static> {
     Line::from(words)
 }
 
-fn find_impl(line: String, lookingfor: String) -> Line<'static> {
+/// Layers `overlay` styles over the styles of `line`, char by char, so
+/// highlights can be combined with syntax colouring. Overlay ranges are char
+/// columns of the whole line, gutter included.
+fn merge_spans(line: Line<'static>, overlay: &[(Range<usize>, Style)]) -> Line<'static> {
     let mut words: Vec<Span> = Vec::new();
+    let mut column = 0;
 
-    let mut last_index = 0;
-
-    for (start, part) in line.match_indices(&lookingfor) {
-        if start > last_index {
-            words.push(Span::raw(line[last_index..start].to_string()));
+    for span in line.spans {
+        for c in span.content.chars() {
+            let style = overlay
+                .iter()
+                .filter(|(range, _)| range.contains(&column))
+                .fold(span.style, |style, (_, overlay_style)| {
+                    style.patch(*overlay_style)
+                });
+            match words.last_mut() {
+                Some(last) if last.style == style => last.content.to_mut().push(c),
+                _ => words.push(Span::styled(c.to_string(), style)),
+            }
+            column += 1;
         }
+    }
 
-        words.push(Span::styled(
-            part.to_string(),
-            Style::default().bg(Color::White).fg(Color::Black),
-        ));
+    Line::from(words)
+}
 
-        last_index = start + part.len();
-    }
+/// Highlights `matches` on a syntax highlighted line whose text starts
+/// after a gutter of `offset` chars.
+fn find_impl(line: Line<'static>, matches: &[Match], offset: usize) -> Line<'static> {
+    let highlight = Style::default().bg(Color::White).fg(Color::Black);
+    let overlay: Vec<(Range<usize>, Style)> = matches
+        .iter()
+        .map(|found| (offset + found.start..offset + found.end, highlight))
+        .collect();
+    merge_spans(line, &overlay)
+}
 
-    if last_index < line.len() {
-        words.push(Span::raw(line[last_index..].to_string()));
+fn undo_tree_impl(entry: Entry, current: usize, selected: bool) -> Line<'static> {
+    let marker = if entry.seq == current { "*" } else { " " };
+    let origin = match entry.parent {
+        Some(parent) => format!("from {parent:<4}"),
+        None => "original ".to_string(),
+    };
+    let age = SystemTime::now()
+        .duration_since(entry.time)
+        .unwrap_or_default()
+        .as_secs();
+    let age = match age {
+        0..60 => format!("{age}s ago"),
+        60..3600 => format!("{}m ago", age / 60),
+        3600..86400 => format!("{}h ago", age / 3600),
+        _ => format!("{}d ago", age / 86400),
+    };
+    let tip = if entry.is_tip { "branch tip" } else { "" };
+    let line = format!(
+        "{marker} {:>4}  {origin}  {:>4} edits  {age:>8}  {tip}",
+        entry.seq, entry.edits
+    );
+    if selected {
+        Line::from(Span::styled(
+            line,
+            Style::default().bg(Color::White).fg(Color::Black),
+        ))
+    } else {
+        Line::from(line)
     }
+}
 
-    Line::from(words)
+fn grep_impl(hit: &Hit, selected: bool) -> Line<'static> {
+    let location = format!("{}:{}:{}: ", hit.path, hit.line, hit.column);
+    if selected {
+        let highlight = Style::default().bg(Color::White).fg(Color::Black);
+        Line::from(vec![
+            Span::styled(location, highlight),
+            Span::styled(hit.text.clone(), highlight),
+        ])
+    } else {
+        Line::from(vec![
+            Span::styled(location, Style::default().fg(Color::LightBlue)),
+            Span::raw(hit.text.clone()),
+        ])
+    }
 }
 
-fn select_impl(line: String, startp: u32, endp: u32) -> Line<'static> {
-    Line::from("select mode")
+/// A row of the hex view: the offset, the bytes in hex and the same bytes
+/// as ASCII. Changed bytes are red and the byte under the cursor is marked
+/// in the ASCII column.
+fn hex_impl(hex: &HexEditor, row: usize) -> Line<'static> {
+    let start = row * BYTES_PER_ROW;
+    let end = (start + BYTES_PER_ROW).min(hex.bytes.len());
+    let style = |index: usize| {
+        if hex.bytes[index] != hex.saved[index] {
+            Style::default().fg(Color::LightRed)
+        } else {
+            Style::default()
+        }
+    };
+    let mut spans = vec![Span::styled(
+        format!("{start:08x}  "),
+        Style::default().fg(Color::LightBlue),
+    )];
+    for index in start..start + BYTES_PER_ROW {
+        if index % BYTES_PER_ROW == BYTES_PER_ROW / 2 {
+            spans.push(Span::raw(" "));
+        }
+        match hex.bytes.get(index) {
+            Some(byte) => spans.push(Span::styled(format!("{byte:02x} "), style(index))),
+            None => spans.push(Span::raw("   ")),
+        }
+    }
+    spans.push(Span::raw(" |"));
+    for index in start..end {
+        let byte = hex.bytes[index];
+        let c = if byte.is_ascii_graphic() || byte == b' ' {
+            byte as char
+        } else {
+            '.'
+        };
+        let style = if index == hex.cursor {
+            Style::default().bg(Color::White).fg(Color::Black)
+        } else {
+            style(index)
+        };
+        spans.push(Span::styled(c.to_string(), style));
+    }
+    spans.push(Span::raw("|"));
+    Line::from(spans)
 }
 
-#[derive(Clone)]
-struct History {
-    code: Vec<String>,
-    line_pos: usize,
-    col_pos: usize,
+/// Highlights columns `startp..endp` of `line`. An `endp` past the end of
+/// the line means the line break is selected too, shown as a trailing cell.
+fn select_impl(line: String, startp: usize, endp: usize) -> Line<'static> {
+    let highlight = Style::default().bg(Color::White).fg(Color::Black);
+    let len = line.chars().count();
+    if startp > len || endp <= startp {
+        return Line::from(line);
+    }
+    let mut words: Vec<Span> = Vec::new();
+
+    let before: String = line.chars().take(startp).collect();
+    let selected: String = line.chars().skip(startp).take(endp - startp).collect();
+    let after: String = line.chars().skip(endp).collect();
+    words.push(Span::raw(before));
+    words.push(Span::styled(selected, highlight));
+    if endp > len {
+        words.push(Span::styled(" ", highlight));
+    }
+    words.push(Span::raw(after));
+
+    Line::from(words)
 }
 
 struct App {
-    code: Vec<String>,
+    code: Buffer,
     column_index: usize,
     line_index: usize,
     input_mode: InputMode,
@@ -147,25 +341,97 @@ struct App {
     scroll_hofst: usize,
     info_text: String,
     save_path: String,
-    file_open_text: Vec<String>,
+    file_open_text: String,
+    /// Line endings, final newline and BOM to save the file with.
+    file_format: FileFormat,
+    /// The format the file was last saved or loaded in.
+    saved_format: FileFormat,
     file_opened: bool,
+    undo_file: bool,
+    swap_file: bool,
+    /// Hash of the text last written to the recovery file, while there is
+    /// one from this session.
+    swap_hash: Option<u64>,
+    swap_time: Instant,
     saved: bool,
+    /// The undo tree state the file was last saved or loaded in.
+    saved_revision: usize,
     find_str: String,
-    history_undo: Vec<History>,
-    history_redo: Vec<History>,
+    search_origin: Cursor,
+    search_options: SearchOptions,
+    search_error: Option<String>,
+    search_matches: Vec<Match>,
+    /// The undo tree state `search_matches` was found in, if still valid.
+    search_state: Option<usize>,
+    history: History,
+    /// Whether text was typed into the open undo step. Until then a pause
+    /// does not end the step, so the text replacing a selection changed
+    /// with `c`, `I` or `A` stays in one step with it.
+    typed: bool,
+    undo_tree_index: usize,
+    select_anchor: Cursor,
+    block_insert: Option<BlockInsert>,
+    registers: Registers,
+    register_name: Option<char>,
+    pending_register: bool,
+    clipboard: Clipboard,
+    message: Option<String>,
+    substitute_str: String,
+    /// The replacements of a running substitution, waiting for answers.
+    substitution: Option<Confirm>,
+    /// Lines of the last selection, for the `'<,'>` range.
+    last_selection: Option<RangeInclusive<usize>>,
+    grep_str: String,
+    grep_hits: Vec<Hit>,
+    grep_index: usize,
+    command_line: CommandLine,
+    /// The file's bytes while in hex mode.
+    hex: Option<HexEditor>,
+    /// Spaces inserted by `Tab` and used for indenting.
+    tabstop: usize,
+}
+
+/// A block insert or append in progress, see `App::block_insert`.
+struct BlockInsert {
+    lines: RangeInclusive<usize>,
+    column: usize,
+    pad: bool,
+}
+
+#[derive(Clone, Copy)]
+enum SelectKind {
+    Char,
+    Line,
+    Block,
 }
 
 enum InputMode {
     Normal,
     Editing,
     Find,
-    Select,
+    Select(SelectKind),
+    UndoTree,
+    Substitute,
+    Grep,
+    GrepResults,
+    Command,
+    /// Asks what to do with unsaved changes before quitting.
+    Quit,
+    /// Shows and overwrites the raw bytes of the file.
+    Hex,
 }
 
 impl App {
-    fn new(save_path_arg: String, file_text: Vec<String>, file_opened_arg: bool) -> Self {
+    fn new(
+        save_path_arg: String,
+        file_text: String,
+        file_format: FileFormat,
+        file_opened_arg: bool,
+        undo_file_arg: bool,
+        swap_file_arg: bool,
+    ) -> Self {
         Self {
-            code: vec![String::new()],
+            code: Buffer::new(),
             input_mode: InputMode::Normal,
             column_index: 0,
             line_index: 0,
@@ -174,11 +440,40 @@ impl App {
             scroll_hofst: 0,
             save_path: save_path_arg,
             file_open_text: file_text,
+            file_format,
+            saved_format: file_format,
             file_opened: file_opened_arg,
+            undo_file: undo_file_arg,
+            swap_file: swap_file_arg,
+            swap_hash: None,
+            swap_time: Instant::now(),
             saved: false,
+            saved_revision: 0,
             find_str: String::new(),
-            history_undo: Vec::new(),
-            history_redo: Vec::new(),
+            search_origin: (0, 0),
+            search_options: SearchOptions::default(),
+            search_error: None,
+            search_matches: Vec::new(),
+            search_state: None,
+            history: History::default(),
+            typed: false,
+            undo_tree_index: 0,
+            select_anchor: (0, 0),
+            block_insert: None,
+            registers: Registers::default(),
+            register_name: None,
+            pending_register: false,
+            clipboard: Clipboard::from_env(),
+            message: None,
+            substitute_str: String::new(),
+            substitution: None,
+            last_selection: None,
+            grep_str: String::new(),
+            grep_hits: Vec::new(),
+            grep_index: 0,
+            command_line: CommandLine::default(),
+            hex: None,
+            tabstop: 2,
         }
     }
 
@@ -189,12 +484,7 @@ impl App {
     }
 
     fn move_cursor_right(&mut self) {
-        let line_len = self
-            .code
-            .get(self.line_index)
-            .map(|line| line.chars().count())
-            .unwrap_or(0);
-        if self.column_index < line_len {
+        if self.column_index < self.code.line_len(self.line_index) {
             self.column_index += 1;
         }
     }
@@ -207,305 +497,1750 @@ impl App {
     }
 
     fn move_cursor_down(&mut self) {
-        if self.line_index + 1 < self.code.len() {
+        if self.line_index + 1 < self.code.len_lines() {
             self.line_index += 1;
             self.column_index = self.clamp_column_index(self.line_index, self.column_index);
         }
     }
 
     fn enter_char(&mut self, new_char: char) {
-        let index = self.byte_index();
-        self.code[self.line_index].insert(index, new_char);
+        self.insert_text(self.char_index(), &new_char.to_string());
         self.move_cursor_right();
     }
 
-    fn byte_index(&self) -> usize {
-        self.code[self.line_index]
-            .char_indices()
-            .map(|(i, _)| i)
-            .nth(self.column_index)
-            .unwrap_or(self.code[self.line_index].len())
+    fn char_index(&self) -> usize {
+        self.code.pos_to_char(self.line_index, self.column_index)
     }
 
     fn delete_char(&mut self) {
         let is_not_cursor_leftmost = self.column_index != 0;
         if is_not_cursor_leftmost {
-            let current_index = self.column_index;
-            let from_left_to_current_index = current_index - 1;
-            let before_char_to_delete = self.code[self.line_index]
-                .chars()
-                .take(from_left_to_current_index);
-            let after_char_to_delete = self.code[self.line_index].chars().skip(current_index);
-            self.code[self.line_index] =
-                before_char_to_delete.chain(after_char_to_delete).collect();
+            let current_index = self.char_index();
+            self.remove_text(current_index - 1..current_index);
             self.move_cursor_left();
         }
     }
 
     fn clamp_column_index(&self, line_index: usize, column_index: usize) -> usize {
-        let line_length = self
-            .code
-            .get(line_index)
-            .map(|line| line.chars().count())
-            .unwrap_or(0);
-        column_index.clamp(0, line_length)
+        column_index.clamp(0, self.code.line_len(line_index))
     }
 
     fn new_line(&mut self) {
-        let line = self.code[self.line_index].clone();
-        let byte_index = line
-            .char_indices()
-            .nth(self.column_index)
-            .map(|(i, _)| i)
-            .unwrap_or(line.len());
-
-        let (left, right) = line.split_at(byte_index);
-        self.code[self.line_index] = left.to_string();
+        self.insert_text(self.char_index(), "\n");
         self.line_index += 1;
-        self.code.insert(self.line_index, right.to_string());
         self.column_index = 0;
     }
 
-    fn save_file(&mut self) {
+    /// Saves the buffer, reporting a failure in the status bar. Returns
+    /// whether the file was written.
+    fn save_file(&mut self) -> bool {
+        self.commit_change();
+        let result = save::write_atomic(Path::new(&self.save_path), |file| {
+            self.file_format.write(self.code.chunks(), file)
+        });
+        if let Err(error) = result {
+            self.message = Some(format!(" Cannot save <{}>: {error} ", self.save_path));
+            return false;
+        }
         self.saved = true;
-        write(&self.save_path, self.code.join("\n")).expect("");
+        self.saved_revision = self.history.current();
+        self.saved_format = self.file_format;
+        if self.swap_hash.take().is_some() {
+            swap::remove(&self.save_path);
+        }
+        if self.undo_file {
+            // The undo file is best-effort; failing to write it must not
+            // get in the way of saving the file itself.
+            let _ = undofile::save(&self.save_path, &self.code, &self.history);
+        }
+        true
+    }
+
+    /// Finds the matches of the search pattern again if the text or the
+    /// pattern changed since they were last found. Each undo tree state is
+    /// a distinct text, so its number tells whether the matches are stale.
+    fn refresh_matches(&mut self) {
+        if self.search_state != Some(self.history.current()) {
+            self.search_matches.clear();
+            self.search_error = None;
+            if !self.find_str.is_empty() {
+                match search::compile(&self.find_str, self.search_options) {
+                    Ok(regex) => self.search_matches = search::find_matches(&self.code, &regex),
+                    Err(_) => self.search_error = Some("invalid pattern".to_string()),
+                }
+            }
+            self.search_state = Some(self.history.current());
+        }
+    }
+
+    /// Moves the cursor to the next or previous match from `from`.
+    fn jump_to_match(&mut self, from: Cursor, forward: bool, inclusive: bool) -> bool {
+        self.refresh_matches();
+        match search::next_match(&self.search_matches, from, forward, inclusive) {
+            Some(index) => {
+                (self.line_index, self.column_index) = self.search_matches[index].cursor();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Jumps to the first match from where the search started, as the
+    /// pattern is typed.
+    fn incremental_search(&mut self) {
+        self.search_state = None;
+        if !self.jump_to_match(self.search_origin, true, true) {
+            (self.line_index, self.column_index) = self.search_origin;
+        }
+    }
+
+    /// The `match 3/17` counter, numbering the match under the cursor.
+    fn match_counter(&mut self) -> String {
+        self.refresh_matches();
+        if let Some(error) = &self.search_error {
+            return error.clone();
+        }
+        let cursor = (self.line_index, self.column_index);
+        let total = self.search_matches.len();
+        match self
+            .search_matches
+            .iter()
+            .position(|found| found.cursor() == cursor)
+        {
+            Some(index) => format!("match {}/{total}", index + 1),
+            None => format!("{total} matches"),
+        }
     }
+
     fn open_file(&mut self) {
-        self.code.clear();
-        for line in &self.file_open_text {
-            self.code.push(line.to_string());
+        // Recorded edits refer to positions in the old text, so a reload
+        // starts a fresh history.
+        self.code = Buffer::from_text(&self.file_open_text);
+        self.history = History::default();
+        self.saved_revision = 0;
+        self.search_state = None;
+        self.line_index = self.line_index.min(self.code.len_lines() - 1);
+        self.column_index = self.clamp_column_index(self.line_index, self.column_index);
+    }
+
+    fn insert_text(&mut self, at: usize, text: &str) {
+        self.code.insert(at, text);
+        self.history.record(Edit::Insert {
+            at,
+            text: text.to_string(),
+        });
+    }
+
+    fn remove_text(&mut self, range: Range<usize>) {
+        let text = self.code.slice(range.clone());
+        self.code.remove(range.clone());
+        self.history.record(Edit::Delete {
+            at: range.start,
+            text,
+        });
+    }
+
+    fn join_line(&mut self, line_index: usize) {
+        if line_index > 0 && line_index < self.code.len_lines() {
+            let start = self.code.line_to_char(line_index);
+            self.remove_text(start - 1..start);
         }
     }
 
-    fn take_snapshot(&mut self) {
-        let snapshot = History {
-            code: self.code.clone(),
-            line_pos: self.line_index,
-            col_pos: self.column_index,
+    fn begin_change(&mut self) {
+        self.history.begin((self.line_index, self.column_index));
+    }
+
+    fn commit_change(&mut self) {
+        self.history.commit((self.line_index, self.column_index));
+    }
+
+    /// Keeps a run of typing in one undo step, starting a new step if
+    /// none is open or typing has paused.
+    fn continue_change(&mut self) {
+        let split = match self.history.idle_for() {
+            Some(idle_for) => self.typed && idle_for > UNDO_GROUP_PAUSE,
+            None => true,
         };
-        self.history_undo.push(snapshot);
-        self.history_redo.clear();
+        if split {
+            self.commit_change();
+            self.begin_change();
+        }
+        self.typed = true;
     }
-    fn undo(&mut self) {
-        if let Some(snapshot) = self.history_undo.pop() {
-            let current = History {
-                code: self.code.clone(),
-                line_pos: self.line_index,
-                col_pos: self.column_index,
-            };
-            self.history_redo.push(current);
 
-            self.code = snapshot.code;
-            self.line_index = snapshot.line_pos;
-            self.column_index = snapshot.col_pos;
+    fn restore_cursor(&mut self, cursor: Option<Cursor>) {
+        if let Some((line_index, column_index)) = cursor {
+            self.line_index = line_index;
+            self.column_index = column_index;
         }
     }
 
+    fn undo(&mut self) {
+        let cursor = self.history.undo(&mut self.code);
+        self.restore_cursor(cursor);
+    }
+
     fn redo(&mut self) {
-        if let Some(snapshot) = self.history_redo.pop() {
-            let current = History {
-                code: self.code.clone(),
-                line_pos: self.line_index,
-                col_pos: self.column_index,
-            };
-            self.history_undo.push(current);
+        let cursor = self.history.redo(&mut self.code);
+        self.restore_cursor(cursor);
+    }
+
+    fn undo_earlier(&mut self) {
+        let cursor = self.history.earlier(&mut self.code);
+        self.restore_cursor(cursor);
+    }
 
-            self.code = snapshot.code;
-            self.line_index = snapshot.line_pos;
-            self.column_index = snapshot.col_pos;
+    fn undo_later(&mut self) {
+        let cursor = self.history.later(&mut self.code);
+        self.restore_cursor(cursor);
+    }
+
+    fn undo_goto(&mut self, seq: usize) {
+        let cursor = self.history.goto(seq, &mut self.code);
+        self.restore_cursor(cursor);
+    }
+
+    fn yank_line(&mut self) {
+        let register = Register {
+            text: self.code.line_string(self.line_index),
+            linewise: true,
+        };
+        self.store_register(register, false);
+    }
+
+    /// Inserts pasted text verbatim as one undo step, instead of replaying
+    /// it through the key handlers.
+    fn paste_text(&mut self, text: &str) {
+        let text = text.replace("\r\n", "\n").replace('\r', "\n");
+        self.commit_change();
+        self.begin_change();
+        let at = self.char_index();
+        self.insert_text(at, &text);
+        self.set_cursor_char(at + text.chars().count());
+        self.commit_change();
+    }
+
+    /// Stores yanked or deleted text in the register picked with `"`, or
+    /// the unnamed and numbered registers. The `+` register also copies it
+    /// to the system clipboard.
+    fn store_register(&mut self, register: Register, delete: bool) {
+        let name = self.register_name.take();
+        if name == Some('+') {
+            let mut text = register.text.clone();
+            if register.linewise {
+                text.push('\n');
+            }
+            if let Err(error) = self.clipboard.copy(&text) {
+                self.message = Some(format!(" Clipboard error: {error} "));
+            }
+        }
+        if delete {
+            self.registers.delete(name, register);
+        } else {
+            self.registers.yank(name, register);
         }
     }
 
-    fn delete_line(&mut self) {
-        self.code[self.line_index] = "".to_string();
+    fn load_register(&mut self) -> Option<Register> {
+        let name = self.register_name.take();
+        if name != Some('+') {
+            return self.registers.get(name).cloned();
+        }
+        match self.clipboard.paste() {
+            // Clipboard text ending in a line break is put as whole lines.
+            Ok(mut text) => {
+                let linewise = text.ends_with('\n');
+                if linewise {
+                    text.pop();
+                }
+                Some(Register { text, linewise })
+            }
+            Err(error) => {
+                self.message = Some(format!(" Clipboard error: {error} "));
+                None
+            }
+        }
     }
 
-    fn run(mut self, mut terminal: DefaultTerminal) -> Result<()> {
-        if self.file_opened {
-            self.open_file();
+    /// Puts the register after the cursor, or before it with `before`.
+    /// Line-wise text goes on new lines below or above the current one.
+    fn put(&mut self, before: bool) {
+        let Some(register) = self.load_register() else {
+            return;
+        };
+        if register.linewise {
+            let line_index = if before {
+                self.line_index
+            } else {
+                self.line_index + 1
+            };
+            if line_index < self.code.len_lines() {
+                let at = self.code.line_to_char(line_index);
+                self.insert_text(at, &format!("{}\n", register.text));
+            } else {
+                let at = self.code.len_chars();
+                self.insert_text(at, &format!("\n{}", register.text));
+            }
+            self.line_index = line_index;
+            self.column_index = 0;
+        } else {
+            let len = self.code.line_len(self.line_index);
+            let column = if before {
+                self.column_index
+            } else {
+                (self.column_index + 1).min(len)
+            };
+            let at = self.code.pos_to_char(self.line_index, column);
+            self.insert_text(at, &register.text);
+            self.set_cursor_char(at + register.text.chars().count());
         }
-        loop {
-            terminal.draw(|frame| self.draw(frame))?;
+    }
 
-            if let Event::Key(key) = event::read()? {
-                match self.input_mode {
-                    InputMode::Normal => match key.code {
-                        KeyCode::Char('i') => {
-                            self.input_mode = InputMode::Editing;
-                        }
-                        KeyCode::Char('q') => {
-                            return Ok(());
-                        }
-                        KeyCode::Char('s') => self.save_file(),
-                        KeyCode::Char('o') => self.open_file(),
-                        KeyCode::Char('/') => self.input_mode = InputMode::Find,
-                        KeyCode::Char('d') => self.delete_line(),
-                        KeyCode::Char('u') => self.undo(),
-                        KeyCode::Char('r') => self.redo(),
-                        KeyCode::Char('v') => self.input_mode = InputMode::Select,
-                        KeyCode::Left => self.move_cursor_left(),
-                        KeyCode::Right => self.move_cursor_right(),
-                        KeyCode::Up => self.move_cursor_up(),
-                        KeyCode::Down => self.move_cursor_down(),
-                        KeyCode::Home => self.column_index = 0,
-                        KeyCode::End => self.column_index = self.code[self.line_index].len(),
-                        _ => {}
-                    },
-                    InputMode::Editing if key.kind == KeyEventKind::Press => match key.code {
-                        KeyCode::Enter => {
-                            self.take_snapshot();
-                            self.new_line();
-                        }
-                        KeyCode::Char(to_insert) => {
-                            self.take_snapshot();
+    /// Lines touched by the selection.
+    fn selected_lines(&self) -> RangeInclusive<usize> {
+        let anchor_line = self.select_anchor.0;
+        anchor_line.min(self.line_index)..=anchor_line.max(self.line_index)
+    }
 
-                            self.saved = false;
-                            self.enter_char(to_insert);
+    /// Columns covered by a block selection.
+    fn block_columns(&self) -> Range<usize> {
+        let anchor_column = self.select_anchor.1;
+        anchor_column.min(self.column_index)..anchor_column.max(self.column_index) + 1
+    }
 
-                            self.history_redo.clear();
-                        }
-                        KeyCode::Home => self.column_index = 0,
-                        KeyCode::End => self.column_index = self.code[self.line_index].len(),
-                        KeyCode::Backspace => {
-                            self.take_snapshot();
-                            if !self.code.is_empty() && self.line_index < self.code.len() {
-                                if self.code[self.line_index].is_empty() && self.line_index != 0 {
-                                    self.code.remove(self.line_index);
-                                    self.move_cursor_up();
-                                    self.column_index = self.code[self.line_index].len();
-                                } else if self.column_index >= 2
-                                    && self.code[self.line_index]
-                                        .chars()
-                                        .nth(self.column_index - 1)
-                                        .map(|c| c == ' ')
-                                        .unwrap_or(false)
-                                    && self.code[self.line_index]
-                                        .chars()
-                                        .nth(self.column_index - 2)
-                                        .map(|c| c == ' ')
-                                        .unwrap_or(false)
-                                {
-                                    self.delete_char();
-                                    self.delete_char();
-                                } else if self.column_index == 0
-                                    && self.code[self.line_index].is_empty()
-                                {
-                                    if self.line_index > 0 {
-                                        let current_line = self.code[self.line_index].clone();
-                                        self.code.remove(self.line_index);
-                                        self.move_cursor_up();
-                                        self.code[self.line_index].push_str(&current_line);
-                                    }
-                                } else if self.column_index > 0 {
-                                    self.delete_char();
-                                }
-                            }
-                        }
-                        KeyCode::Tab => {
-                            self.take_snapshot();
-                            self.enter_char(' ');
-                            self.enter_char(' ');
-                        }
-                        KeyCode::Left => self.move_cursor_left(),
-                        KeyCode::Right => self.move_cursor_right(),
-                        KeyCode::Up => self.move_cursor_up(),
-                        KeyCode::Down => self.move_cursor_down(),
-                        KeyCode::Esc => self.input_mode = InputMode::Normal,
-                        _ => {}
-                    },
-                    InputMode::Find if key.kind == KeyEventKind::Press => match key.code {
-                        KeyCode::Char(to_find) => {
-                            self.saved = false;
-                            self.find_str.push(to_find);
-                            self.input_mode = InputMode::Find;
-                        }
-                        KeyCode::Backspace => {
-                            self.find_str.pop();
-                        }
-                        KeyCode::Esc => self.input_mode = InputMode::Normal,
-                        KeyCode::Left => self.move_cursor_left(),
-                        KeyCode::Right => self.move_cursor_right(),
-                        KeyCode::Up => self.move_cursor_up(),
-                        KeyCode::Down => self.move_cursor_down(),
+    /// The selected text as char ranges in document order: one range for
+    /// character-wise and line-wise selections, one per line for blocks.
+    fn selection_ranges(&self, kind: SelectKind) -> Vec<Range<usize>> {
+        let lines = self.selected_lines();
+        match kind {
+            SelectKind::Char => {
+                let (anchor_line, anchor_column) = self.select_anchor;
+                let anchor = self.code.pos_to_char(anchor_line, anchor_column);
+                let cursor = self.char_index();
+                let end = anchor.max(cursor) + 1;
+                let range = anchor.min(cursor)..end.min(self.code.len_chars());
+                vec![range]
+            }
+            SelectKind::Line => {
+                let range = self.line_span(lines);
+                vec![range]
+            }
+            SelectKind::Block => {
+                let columns = self.block_columns();
+                lines
+                    .map(|line_index| {
+                        let start = self.code.line_to_char(line_index);
+                        let len = self.code.line_len(line_index);
+                        start + columns.start.min(len)..start + columns.end.min(len)
+                    })
+                    .collect()
+            }
+        }
+    }
 
-                        _ => {}
-                    },
-                    InputMode::Select if key.kind == KeyEventKind::Press => match key.code {
-                        KeyCode::Left => {}
-                        KeyCode::Right => {}
-                        KeyCode::Up => {}
-                        KeyCode::Down => {}
-                        KeyCode::Esc => self.input_mode = InputMode::Normal,
-                        _ => {}
-                    },
-                    InputMode::Editing => {}
-                    InputMode::Find => {}
-                    InputMode::Select => {}
-                }
+    /// The chars of whole lines, with the line break after the last one.
+    fn line_span(&self, lines: RangeInclusive<usize>) -> Range<usize> {
+        let start = self.code.line_to_char(*lines.start());
+        let end = match lines.end() + 1 {
+            next if next < self.code.len_lines() => self.code.line_to_char(next),
+            _ => self.code.len_chars(),
+        };
+        start..end
+    }
+
+    /// Columns of `line_index` to highlight while selecting.
+    fn selection_columns(&self, kind: SelectKind, line_index: usize) -> (usize, usize) {
+        if !self.selected_lines().contains(&line_index) {
+            return (0, 0);
+        }
+        match kind {
+            SelectKind::Char => {
+                let start = self.code.line_to_char(line_index);
+                let range = &self.selection_ranges(kind)[0];
+                (
+                    range.start.saturating_sub(start),
+                    range.end.saturating_sub(start),
+                )
+            }
+            SelectKind::Line => (0, self.code.line_len(line_index) + 1),
+            SelectKind::Block => {
+                let columns = self.block_columns();
+                (
+                    columns.start,
+                    columns.end.min(self.code.line_len(line_index)),
+                )
             }
         }
     }
 
-    pub fn normal_info_text(&mut self) {
-        self.info_text = format!(
-            "  <{}> - edit: i, save: s, find: /, undo-redo: u-r, quit: q ",
-            self.save_path
-        );
+    fn set_cursor_char(&mut self, char_index: usize) {
+        (self.line_index, self.column_index) = self.code.char_to_pos(char_index);
     }
 
-    pub fn edit_info_text(&mut self) {
-        self.info_text = format!(
-            " <{}> - x:{}|y:{} - quit: ESC ",
-            self.save_path, self.column_index, self.line_index,
-        );
+    /// Moves the cursor to the top left corner of the selection.
+    fn cursor_to_selection_start(&mut self, kind: SelectKind) {
+        match kind {
+            SelectKind::Char => {
+                let start = self.selection_ranges(kind)[0].start;
+                self.set_cursor_char(start);
+            }
+            SelectKind::Line => {
+                self.line_index = *self.selected_lines().start();
+                self.column_index = 0;
+            }
+            SelectKind::Block => {
+                self.line_index = *self.selected_lines().start();
+                self.column_index = self
+                    .block_columns()
+                    .start
+                    .min(self.code.line_len(self.line_index));
+            }
+        }
     }
 
-    pub fn find_info_text(&mut self) {
-        self.info_text = format!(
-            " Search in <{} for quit: ESC> : {}",
-            self.save_path, self.find_str
-        );
+    /// The selected text, with the lines of a block joined by line breaks.
+    fn selected_text(&self, kind: SelectKind) -> String {
+        let ranges = self.selection_ranges(kind);
+        let pieces: Vec<String> = ranges
+            .into_iter()
+            .map(|range| self.code.slice(range))
+            .collect();
+        pieces.join("\n")
     }
 
-    pub fn save_info_text(&mut self) {
-        self.info_text = format!(" File saved to <{}>", self.save_path);
+    /// The selection as register contents; line-wise selections are
+    /// stored without their final line break.
+    fn selection_register(&self, kind: SelectKind) -> Register {
+        let mut text = self.selected_text(kind);
+        let linewise = matches!(kind, SelectKind::Line);
+        if linewise && text.ends_with('\n') {
+            text.pop();
+        }
+        Register { text, linewise }
     }
 
-    pub fn select_info_text(&mut self) {
-        self.info_text = format!(
-            "Selection x:{}, y:{}, <{}>",
-            self.column_index, self.line_index, self.save_path
-        );
+    fn yank_selection(&mut self, kind: SelectKind) {
+        let register = self.selection_register(kind);
+        self.store_register(register, false);
+        self.cursor_to_selection_start(kind);
     }
 
-    fn draw(&mut self, frame: &mut Frame) {
-        let vertical = Layout::vertical([Constraint::Length(1), Constraint::Min(1)]);
-        let [status_area, edit_area] = vertical.areas(frame.area());
-        // let helped_layout = Layout::vertical([
-        // Constraint::Length(1),
-        // Constraint::Length(2),
-        // Constraint::Min(1),
-        // ]);
+    fn delete_selection(&mut self, kind: SelectKind) {
+        if let SelectKind::Line = kind {
+            self.delete_lines(self.selected_lines());
+            return;
+        }
+        let register = self.selection_register(kind);
+        self.store_register(register, true);
+        let ranges = self.selection_ranges(kind);
+        self.cursor_to_selection_start(kind);
+        for range in ranges.into_iter().rev() {
+            self.remove_text(range);
+        }
+    }
 
-        match self.input_mode {
-            InputMode::Normal => {
-                if !self.saved {
-                    self.normal_info_text();
-                } else {
-                    self.save_info_text();
-                }
+    /// Deletes whole lines into a line-wise register.
+    fn delete_lines(&mut self, lines: RangeInclusive<usize>) {
+        let first_line = *lines.start();
+        let mut range = self.line_span(lines);
+        let mut text = self.code.slice(range.clone());
+        if text.ends_with('\n') {
+            text.pop();
+        } else if range.start > 0 {
+            // Deleting the last lines also takes the line break before them,
+            // so no empty line is left behind.
+            range.start -= 1;
+        }
+        let register = Register {
+            text,
+            linewise: true,
+        };
+        self.store_register(register, true);
+        self.remove_text(range);
+        self.line_index = first_line.min(self.code.len_lines() - 1);
+        self.column_index = 0;
+    }
+
+    /// Joins `lines` into one, replacing each line break and the indent
+    /// after it with a single space. A single line is joined with the next.
+    fn join_lines(&mut self, lines: RangeInclusive<usize>) {
+        let first_line = *lines.start();
+        let last_line = (*lines.end()).max(first_line + 1);
+        if last_line >= self.code.len_lines() {
+            return;
+        }
+        for line_index in (first_line + 1..=last_line).rev() {
+            let start = self.code.line_to_char(line_index);
+            let indent = (0..self.code.line_len(line_index))
+                .take_while(|&i| {
+                    self.code
+                        .char_at(line_index, i)
+                        .is_some_and(char::is_whitespace)
+                })
+                .count();
+            let joins_text =
+                !self.code.line_is_empty(line_index - 1) && indent < self.code.line_len(line_index);
+            self.remove_text(start - 1..start + indent);
+            if joins_text {
+                self.insert_text(start - 1, " ");
             }
-            InputMode::Editing => self.edit_info_text(),
-            InputMode::Find => self.find_info_text(),
-            InputMode::Select => self.select_info_text(),
         }
+        self.line_index = first_line;
+        self.column_index = self.clamp_column_index(first_line, self.column_index);
+    }
 
-        let (msg, style) = match self.input_mode {
-            InputMode::Normal => (
-                vec![
-                    " Normal ".bg(Color::Yellow),
-                    "".bg(Color::Gray).fg(Color::Yellow),
-                    "".fg(Color::Gray).bg(Color::DarkGray),
+    /// Deletes the selection and leaves the cursor where the replacement
+    /// text goes. Line-wise changes keep one empty line, and block changes
+    /// type the replacement on every selected line.
+    fn change_selection(&mut self, kind: SelectKind) {
+        match kind {
+            SelectKind::Char => self.delete_selection(kind),
+            SelectKind::Line => {
+                let register = self.selection_register(kind);
+                self.store_register(register, true);
+                let lines = self.selected_lines();
+                let start = self.code.line_to_char(*lines.start());
+                let end = self.code.line_to_char(*lines.end()) + self.code.line_len(*lines.end());
+                self.remove_text(start..end);
+                self.line_index = *lines.start();
+                self.column_index = 0;
+            }
+            SelectKind::Block => {
+                let lines = self.selected_lines();
+                let column = self.block_columns().start;
+                self.delete_selection(kind);
+                self.block_insert(lines, column, false);
+            }
+        }
+    }
+
+    /// Starts typing at `column` of the first line; the text typed there is
+    /// copied to the other lines when leaving Editing mode. With `pad`,
+    /// shorter lines are filled with spaces up to the column.
+    fn block_insert(&mut self, lines: RangeInclusive<usize>, column: usize, pad: bool) {
+        let first_line = *lines.start();
+        let len = self.code.line_len(first_line);
+        if len < column {
+            let end = self.code.line_to_char(first_line) + len;
+            self.insert_text(end, &" ".repeat(column - len));
+        }
+        self.line_index = first_line;
+        self.column_index = column;
+        self.block_insert = Some(BlockInsert { lines, column, pad });
+    }
+
+    fn finish_block_insert(&mut self) {
+        let Some(block) = self.block_insert.take() else {
+            return;
+        };
+        let first_line = *block.lines.start();
+        if self.line_index != first_line || self.column_index <= block.column {
+            return;
+        }
+        let start = self.code.line_to_char(first_line);
+        let text = self
+            .code
+            .slice(start + block.column..start + self.column_index);
+        for line_index in block.lines.skip(1) {
+            let len = self.code.line_len(line_index);
+            let line_start = self.code.line_to_char(line_index);
+            if len >= block.column {
+                self.insert_text(line_start + block.column, &text);
+            } else if block.pad {
+                let padding = " ".repeat(block.column - len);
+                self.insert_text(line_start + len, &format!("{padding}{text}"));
+            }
+        }
+    }
+
+    /// Indents every line in `lines` by `tabstop` spaces, the width `Tab`
+    /// inserts, or removes up to that many leading spaces.
+    fn indent_lines(&mut self, lines: RangeInclusive<usize>, outdent: bool) {
+        let first_line = *lines.start();
+        for line_index in lines {
+            let start = self.code.line_to_char(line_index);
+            if outdent {
+                let spaces = (0..self.tabstop)
+                    .take_while(|&i| self.code.char_at(line_index, i) == Some(' '))
+                    .count();
+                self.remove_text(start..start + spaces);
+            } else if !self.code.line_is_empty(line_index) {
+                self.insert_text(start, &" ".repeat(self.tabstop));
+            }
+        }
+        self.line_index = first_line;
+        self.column_index = 0;
+    }
+
+    fn change_case_selection(&mut self, kind: SelectKind, change_case: fn(char) -> String) {
+        for range in self.selection_ranges(kind).into_iter().rev() {
+            let text = self.code.slice(range.clone());
+            let changed: String = text.chars().map(change_case).collect();
+            if changed != text {
+                self.remove_text(range.clone());
+                self.insert_text(range.start, &changed);
+            }
+        }
+        self.cursor_to_selection_start(kind);
+    }
+
+    /// What line addresses like `.` or `'<` refer to.
+    fn range_context(&self) -> range::Context<'_> {
+        range::Context {
+            buffer: &self.code,
+            cursor_line: self.line_index,
+            selection: self.last_selection.clone(),
+            search_options: self.search_options,
+        }
+    }
+
+    /// Runs the `[range]s/pattern/replacement/[flags]` command typed at the
+    /// prompt. All replacements, confirmed one by one or not, make up one
+    /// undo step.
+    fn substitute(&mut self) {
+        self.input_mode = InputMode::Normal;
+        let substitute = match substitute::parse(&self.substitute_str, self.search_options) {
+            Ok(substitute) => substitute,
+            Err(error) => {
+                self.message = Some(format!(" Substitute: {error} "));
+                return;
+            }
+        };
+        let lines = match substitute.lines(&self.range_context()) {
+            Ok(lines) => lines,
+            Err(error) => {
+                self.message = Some(format!(" Substitute: {error} "));
+                return;
+            }
+        };
+        let replacements = substitute.replacements(&self.code, lines);
+        if replacements.is_empty() {
+            self.message = Some(" Substitute: pattern not found ".to_string());
+            return;
+        }
+        self.begin_change();
+        self.substitution = Some(replacements);
+        if substitute.confirm {
+            self.input_mode = InputMode::Substitute;
+            self.show_substitution();
+        } else {
+            self.answer_substitution('a');
+        }
+    }
+
+    /// Replaces the current match of the substitution, leaving the cursor
+    /// at its start.
+    fn replace_current(&mut self) -> bool {
+        let Some((range, text)) = self.substitution.as_ref().and_then(Confirm::current) else {
+            return false;
+        };
+        let start = range.start;
+        if !range.is_empty() {
+            self.remove_text(range);
+        }
+        if !text.is_empty() {
+            self.insert_text(start, &text);
+        }
+        self.set_cursor_char(start);
+        if let Some(substitution) = &mut self.substitution {
+            substitution.advance(true);
+        }
+        true
+    }
+
+    /// Answers for the current match: `y` replaces it, `n` skips it, `a`
+    /// replaces it and every match after it, and `q` stops.
+    fn answer_substitution(&mut self, answer: char) {
+        match answer {
+            'y' => {
+                self.replace_current();
+            }
+            'a' => while self.replace_current() {},
+            _ => {
+                if let Some(substitution) = &mut self.substitution {
+                    match answer {
+                        'n' => substitution.advance(false),
+                        _ => substitution.stop(),
+                    }
+                }
+            }
+        }
+        self.show_substitution();
+    }
+
+    /// Moves the cursor to the match to answer for next, or ends the
+    /// substitution once there are none left.
+    fn show_substitution(&mut self) {
+        let Some(substitution) = &self.substitution else {
+            return;
+        };
+        match substitution.current() {
+            Some((range, _)) => self.set_cursor_char(range.start),
+            None => {
+                let count = substitution.count;
+                self.substitution = None;
+                self.commit_change();
+                self.message = Some(format!(" {count} substitutions "));
+                self.input_mode = InputMode::Normal;
+            }
+        }
+    }
+
+    /// Writes unsaved changes to the recovery file, and removes it again
+    /// once there are none.
+    fn update_swap_file(&mut self) {
+        self.swap_time = Instant::now();
+        if !self.swap_file {
+            return;
+        }
+        if !self.is_modified() {
+            if self.swap_hash.take().is_some() {
+                swap::remove(&self.save_path);
+            }
+            return;
+        }
+        let hash = undofile::content_hash(&self.code);
+        // Like the undo file, the recovery file is best-effort.
+        if self.swap_hash != Some(hash)
+            && swap::save(
+                &self.save_path,
+                &self.code,
+                (self.line_index, self.column_index),
+            )
+            .is_ok()
+        {
+            self.swap_hash = Some(hash);
+        }
+    }
+
+    /// Replaces the buffer with recovered text as one undo step, so undo
+    /// gets back to the file as saved.
+    fn recover(&mut self, swap: Swap) {
+        self.begin_change();
+        self.remove_text(0..self.code.len_chars());
+        self.insert_text(0, &swap.text);
+        let (line, column) = swap.cursor;
+        self.line_index = line.min(self.code.len_lines() - 1);
+        self.column_index = self.clamp_column_index(self.line_index, column);
+        self.commit_change();
+        // The recovery file holds this text, and is removed on save.
+        self.swap_hash = Some(undofile::content_hash(&self.code));
+        self.message = Some(" Recovered unsaved changes, save with s to keep them ".to_string());
+    }
+
+    fn load_undo_file(&mut self) {
+        if self.undo_file {
+            if let Some(history) = undofile::load(&self.save_path, &self.code) {
+                self.history = history;
+                self.saved_revision = self.history.current();
+            }
+        }
+    }
+
+    /// Whether the buffer or its format has changed since it was last saved
+    /// or loaded. Undoing back to the saved state makes it unmodified again.
+    fn is_modified(&self) -> bool {
+        self.history.has_pending_edits()
+            || self.history.current() != self.saved_revision
+            || self.file_format != self.saved_format
+    }
+
+    /// Searches the files under the working directory for the grep pattern
+    /// and lists the hits.
+    fn run_grep(&mut self) {
+        let regex = match search::compile(&self.grep_str, self.search_options) {
+            Ok(regex) => regex,
+            Err(_) => {
+                self.message = Some(" Grep: invalid pattern ".to_string());
+                self.input_mode = InputMode::Normal;
+                return;
+            }
+        };
+        self.grep_hits = grep::grep(Path::new("."), &regex);
+        self.grep_index = 0;
+        if self.grep_hits.is_empty() {
+            self.message = Some(format!(" Grep: no matches for {} ", self.grep_str));
+            self.input_mode = InputMode::Normal;
+        } else {
+            self.input_mode = InputMode::GrepResults;
+        }
+    }
+
+    /// Replaces the buffer with the file at `path`. Unless `force` is set,
+    /// this is refused while the buffer has unsaved changes.
+    fn open_path(&mut self, path: &str, force: bool) -> bool {
+        if !force && self.is_modified() {
+            self.message = Some(" Unsaved changes, save with s first ".to_string());
+            return false;
+        }
+        match read_file(path) {
+            Ok((text, format)) => {
+                if self.swap_hash.take().is_some() {
+                    swap::remove(&self.save_path);
+                }
+                self.save_path = path.to_string();
+                self.file_open_text = text;
+                self.file_format = format;
+                self.saved_format = format;
+                self.file_opened = true;
+                self.saved = false;
+                self.line_index = 0;
+                self.column_index = 0;
+                self.open_file();
+                self.load_undo_file();
+                true
+            }
+            Err(error) => {
+                self.message = Some(format!(" Cannot open {path}: {error} "));
+                false
+            }
+        }
+    }
+
+    /// Binary files are shown with escapes and cannot be saved, so their
+    /// text is not edited, only their bytes in hex mode.
+    fn is_read_only(&self) -> bool {
+        self.file_format.encoding == Encoding::Binary
+    }
+
+    fn refuse_edit(&mut self) {
+        self.message = Some(" Binary file is read-only, edit it in hex mode with x ".to_string());
+    }
+
+    /// Switches to hex mode on the file as saved. Unsaved changes to the
+    /// text would not be in it, so they have to be saved first.
+    fn open_hex(&mut self) {
+        if self.is_modified() {
+            self.message = Some(" Unsaved changes, save with s first ".to_string());
+            return;
+        }
+        match HexEditor::load(&self.save_path) {
+            Ok(hex) => {
+                self.hex = Some(hex);
+                self.scroll_ofst = 0;
+                self.scroll_hofst = 0;
+                self.input_mode = InputMode::Hex;
+            }
+            Err(error) => self.message = Some(format!(" Cannot open {}: {error} ", self.save_path)),
+        }
+    }
+
+    /// Writes the bytes back and reloads the text from them.
+    fn save_hex(&mut self) {
+        let Some(hex) = &mut self.hex else {
+            return;
+        };
+        let path = self.save_path.clone();
+        if let Err(error) = save::write_atomic(Path::new(&path), |file| file.write_all(&hex.bytes))
+        {
+            self.message = Some(format!(" Cannot save <{path}>: {error} "));
+            return;
+        }
+        hex.saved = hex.bytes.clone();
+        let len = hex.bytes.len();
+        if self.open_path(&path, true) {
+            self.message = Some(format!(" Written {len} bytes to <{path}> "));
+        }
+    }
+
+    /// Leaves hex mode, unless that would throw away changed bytes.
+    fn close_hex(&mut self) {
+        if self.hex.as_ref().is_some_and(HexEditor::is_modified) {
+            self.message = Some(" Unsaved changes, save with s or undo with u ".to_string());
+            return;
+        }
+        self.hex = None;
+        self.scroll_ofst = 0;
+        self.scroll_hofst = 0;
+        self.input_mode = InputMode::Normal;
+    }
+
+    /// Writes the buffer, or just `lines` of it, to another file, leaving
+    /// the file being edited as it is. Returns whether the file was written.
+    fn write_copy(&mut self, path: &str, lines: Option<RangeInclusive<usize>>) -> bool {
+        let result = match lines {
+            Some(lines) => {
+                let text: Vec<String> = lines.map(|i| self.code.line_string(i)).collect();
+                let text = text.join("\n");
+                save::write_atomic(Path::new(path), |file| {
+                    self.file_format.write([text.as_str()].into_iter(), file)
+                })
+            }
+            None => save::write_atomic(Path::new(path), |file| {
+                self.file_format.write(self.code.chunks(), file)
+            }),
+        };
+        let written = result.is_ok();
+        self.message = Some(match result {
+            Ok(()) => format!(" Written to <{path}> "),
+            Err(error) => format!(" Cannot write {path}: {error} "),
+        });
+        written
+    }
+
+    /// Runs the `:` command line, returning whether the editor should quit.
+    fn execute_command(&mut self) -> bool {
+        self.input_mode = InputMode::Normal;
+        let text = self.command_line.submit();
+        let parsed = command::parse(&text).and_then(|(range, command)| {
+            let lines = match range {
+                Some(range) => Some(range.resolve(&self.range_context())?),
+                None => None,
+            };
+            Ok((lines, command))
+        });
+        let (lines, command) = match parsed {
+            Ok(parsed) => parsed,
+            Err(error) => {
+                self.message = Some(format!(" {error} "));
+                return false;
+            }
+        };
+        let cursor_line = self.line_index;
+        let lines_or_cursor = lines.clone().unwrap_or(cursor_line..=cursor_line);
+        match command {
+            Command::Delete
+            | Command::Indent { .. }
+            | Command::Join
+            | Command::Substitute(_)
+            | Command::Filter(_)
+                if self.is_read_only() =>
+            {
+                self.refuse_edit();
+            }
+            Command::Write { path: None, .. } if lines.is_some() => {
+                self.message = Some(" Writing a range needs a file name ".to_string());
+            }
+            Command::Write {
+                path: Some(path),
+                quit,
+            } => {
+                return self.write_copy(&path, lines) && quit;
+            }
+            Command::Write { path: None, quit } => return self.save_file() && quit,
+            Command::Edit { path, force } => {
+                let path = path.unwrap_or_else(|| self.save_path.clone());
+                self.open_path(&path, force);
+            }
+            Command::Quit { force } => {
+                if force || !self.is_modified() {
+                    return true;
+                }
+                self.message = Some(" Unsaved changes, add ! to quit anyway ".to_string());
+            }
+            Command::Goto => {
+                self.line_index = *lines_or_cursor.end();
+                self.column_index = self.clamp_column_index(self.line_index, self.column_index);
+            }
+            Command::Delete => {
+                self.begin_change();
+                self.delete_lines(lines_or_cursor);
+                self.commit_change();
+            }
+            Command::Indent { outdent } => {
+                self.begin_change();
+                self.indent_lines(lines_or_cursor, outdent);
+                self.commit_change();
+            }
+            Command::Join => {
+                self.begin_change();
+                self.join_lines(lines_or_cursor);
+                self.commit_change();
+            }
+            Command::Set { name, value } => self.set_option(&name, value.as_deref()),
+            Command::Substitute(text) => {
+                self.substitute_str = text;
+                self.substitute();
+            }
+            Command::Filter(command) => match lines {
+                Some(lines) => self.filter_lines(lines, &command),
+                None => self.insert_command_output(&command),
+            },
+        }
+        false
+    }
+
+    /// Pipes `lines` through a shell command and puts its output in their
+    /// place, as one undo step. On failure the buffer is left alone.
+    fn filter_lines(&mut self, lines: RangeInclusive<usize>, command: &str) {
+        let first_line = *lines.start();
+        let start = self.code.line_to_char(first_line);
+        let end = self.code.line_to_char(*lines.end()) + self.code.line_len(*lines.end());
+        let input = format!("{}\n", self.code.slice(start..end));
+        let output = match filter::run(command, &input) {
+            Ok(output) => output,
+            Err(error) => {
+                self.message = Some(format!(" {error} "));
+                return;
+            }
+        };
+        let output = command_output_text(&output);
+        self.begin_change();
+        self.remove_text(start..end);
+        self.insert_text(start, &output);
+        self.line_index = first_line;
+        self.column_index = 0;
+        self.commit_change();
+        let count = output.lines().count();
+        self.message = Some(format!(" {count} lines filtered through `{command}` "));
+    }
+
+    /// Inserts the output of a shell command at the cursor.
+    fn insert_command_output(&mut self, command: &str) {
+        let output = match filter::run(command, "") {
+            Ok(output) => output,
+            Err(error) => {
+                self.message = Some(format!(" {error} "));
+                return;
+            }
+        };
+        let output = command_output_text(&output);
+        self.begin_change();
+        let at = self.char_index();
+        self.insert_text(at, &output);
+        self.commit_change();
+    }
+
+    /// Sets an option of `:set`, or shows its value when none is given.
+    /// Changing the file format marks the buffer as modified.
+    fn set_option(&mut self, name: &str, value: Option<&str>) {
+        let format = &mut self.file_format;
+        let Some(value) = value else {
+            let value = match name {
+                "tabstop" => self.tabstop.to_string(),
+                "fileencoding" => format.encoding.to_string(),
+                "fileformat" if format.line_ending == LineEnding::Crlf => "dos".to_string(),
+                "fileformat" => "unix".to_string(),
+                "endofline" => on_off(format.final_newline).to_string(),
+                "bomb" => on_off(format.bom).to_string(),
+                _ => return,
+            };
+            self.message = Some(format!(" {name}={value} "));
+            return;
+        };
+        let valid = match (name, value) {
+            ("tabstop", value) => match value.parse::<usize>() {
+                Ok(tabstop) if (1..=16).contains(&tabstop) => {
+                    self.tabstop = tabstop;
+                    true
+                }
+                _ => false,
+            },
+            // Binary files are shown escaped, so converting them would write
+            // the escapes.
+            ("fileencoding", _) if format.encoding == Encoding::Binary => false,
+            ("fileencoding", value) => match Encoding::from_name(value) {
+                Some(encoding) => {
+                    format.encoding = encoding;
+                    true
+                }
+                None => false,
+            },
+            ("fileformat", "unix") => {
+                format.line_ending = LineEnding::Lf;
+                true
+            }
+            ("fileformat", "dos") => {
+                format.line_ending = LineEnding::Crlf;
+                true
+            }
+            ("endofline", "on" | "off") => {
+                format.final_newline = value == "on";
+                true
+            }
+            ("bomb", "on" | "off") => {
+                format.bom = value == "on";
+                true
+            }
+            _ => false,
+        };
+        if !valid {
+            self.message = Some(format!(" Invalid value for {name}: {value} "));
+        }
+    }
+
+    /// Opens the file of the selected hit, unless that would throw away
+    /// unsaved changes, and moves the cursor to the match.
+    fn open_hit(&mut self) {
+        let Some(hit) = self.grep_hits.get(self.grep_index).cloned() else {
+            return;
+        };
+        let same_file = match (
+            fs::canonicalize(&hit.path),
+            fs::canonicalize(&self.save_path),
+        ) {
+            (Ok(hit_path), Ok(save_path)) => hit_path == save_path,
+            _ => hit.path == self.save_path,
+        };
+        if !same_file && !self.open_path(&hit.path, false) {
+            return;
+        }
+        self.line_index = hit.line.min(self.code.len_lines() - 1);
+        self.column_index = self.clamp_column_index(self.line_index, hit.column);
+        self.input_mode = InputMode::Normal;
+    }
+
+    fn run(mut self, mut terminal: DefaultTerminal, recovery: Option<Swap>) -> Result<()> {
+        if self.file_opened {
+            self.open_file();
+            self.load_undo_file();
+        }
+        if let Some(swap) = recovery {
+            self.recover(swap);
+        } else if self.file_format.encoding == Encoding::Binary {
+            self.open_hex();
+        }
+        match self.edit(&mut terminal) {
+            Ok(()) => {
+                if self.swap_hash.is_some() {
+                    swap::remove(&self.save_path);
+                }
+                Ok(())
+            }
+            // Unsaved changes go to the recovery file, to be offered on the
+            // next start.
+            Err(error) if self.swap_file && self.is_modified() => {
+                let cursor = (self.line_index, self.column_index);
+                match swap::save(&self.save_path, &self.code, cursor) {
+                    Ok(path) => Err(error.note(format!(
+                        "Unsaved changes were saved to {}, open the file again to recover them",
+                        path.display()
+                    ))),
+                    Err(_) => Err(error),
+                }
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    fn edit(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+        loop {
+            if self.swap_time.elapsed() >= SWAP_INTERVAL {
+                self.update_swap_file();
+            }
+            swap::track((self.swap_file && self.is_modified()).then(|| {
+                let cursor = (self.line_index, self.column_index);
+                (self.save_path.as_str(), &self.code, cursor)
+            }));
+            if let Some(osc52) = self.clipboard.take_osc52() {
+                let backend = terminal.backend_mut();
+                backend.write_all(osc52.as_bytes())?;
+                backend.flush()?;
+            }
+            terminal.draw(|frame| self.draw(frame))?;
+
+            if !event::poll(SWAP_INTERVAL.saturating_sub(self.swap_time.elapsed()))? {
+                continue;
+            }
+            let event = event::read()?;
+            if let (Event::Paste(text), InputMode::Editing) = (&event, &self.input_mode) {
+                if self.is_read_only() {
+                    self.refuse_edit();
+                } else {
+                    self.paste_text(text);
+                }
+            }
+            if let Event::Key(key) = event {
+                self.message = None;
+                match self.input_mode {
+                    // `"` followed by a register name picks the register the
+                    // next yank, delete or put uses.
+                    InputMode::Normal | InputMode::Select(_) if self.pending_register => {
+                        self.pending_register = false;
+                        self.register_name = match key.code {
+                            KeyCode::Char(name) if Registers::is_valid_name(name) => Some(name),
+                            _ => None,
+                        };
+                    }
+                    InputMode::Normal => match key.code {
+                        KeyCode::Char('i' | 'd' | 'p' | 'P' | 'S' | '!') if self.is_read_only() => {
+                            self.refuse_edit();
+                        }
+                        KeyCode::Char('i') => {
+                            self.input_mode = InputMode::Editing;
+                        }
+                        KeyCode::Char('x') => self.open_hex(),
+                        KeyCode::Char('q') if self.is_modified() => {
+                            self.input_mode = InputMode::Quit;
+                        }
+                        KeyCode::Char('q') => {
+                            return Ok(());
+                        }
+                        KeyCode::Char('s') => {
+                            self.save_file();
+                        }
+                        KeyCode::Char('o') => {
+                            let path = self.save_path.clone();
+                            self.open_path(&path, false);
+                        }
+                        KeyCode::Char('/') => {
+                            self.search_origin = (self.line_index, self.column_index);
+                            self.find_str.clear();
+                            self.search_state = None;
+                            self.input_mode = InputMode::Find;
+                        }
+                        KeyCode::Char('S') => {
+                            self.substitute_str.clear();
+                            self.input_mode = InputMode::Substitute;
+                        }
+                        KeyCode::Char(':') => {
+                            self.command_line.start("");
+                            self.input_mode = InputMode::Command;
+                        }
+                        KeyCode::Char('!') => {
+                            self.command_line.start(".!");
+                            self.input_mode = InputMode::Command;
+                        }
+                        KeyCode::Char('g') => {
+                            self.grep_str.clear();
+                            self.input_mode = InputMode::Grep;
+                        }
+                        KeyCode::Char('G') if !self.grep_hits.is_empty() => {
+                            self.input_mode = InputMode::GrepResults;
+                        }
+                        KeyCode::Char('n') => {
+                            let cursor = (self.line_index, self.column_index);
+                            self.jump_to_match(cursor, true, false);
+                        }
+                        KeyCode::Char('N') => {
+                            let cursor = (self.line_index, self.column_index);
+                            self.jump_to_match(cursor, false, false);
+                        }
+                        KeyCode::Char('d') => {
+                            self.begin_change();
+                            self.delete_lines(self.line_index..=self.line_index);
+                            self.commit_change();
+                        }
+                        KeyCode::Char('"') => self.pending_register = true,
+                        KeyCode::Char('y') => self.yank_line(),
+                        KeyCode::Char('p') | KeyCode::Char('P') => {
+                            self.begin_change();
+                            self.put(key.code == KeyCode::Char('P'));
+                            self.commit_change();
+                        }
+                        KeyCode::Char('u') => self.undo(),
+                        KeyCode::Char('r') => self.redo(),
+                        KeyCode::Char('-') => self.undo_earlier(),
+                        KeyCode::Char('+') => self.undo_later(),
+                        KeyCode::Char('U') => {
+                            self.undo_tree_index = self.history.current();
+                            self.input_mode = InputMode::UndoTree;
+                        }
+                        KeyCode::Char('v') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            self.select_anchor = (self.line_index, self.column_index);
+                            self.input_mode = InputMode::Select(SelectKind::Block);
+                        }
+                        KeyCode::Char('v') => {
+                            self.select_anchor = (self.line_index, self.column_index);
+                            self.input_mode = InputMode::Select(SelectKind::Char);
+                        }
+                        KeyCode::Char('V') => {
+                            self.select_anchor = (self.line_index, self.column_index);
+                            self.input_mode = InputMode::Select(SelectKind::Line);
+                        }
+                        KeyCode::Left => self.move_cursor_left(),
+                        KeyCode::Right => self.move_cursor_right(),
+                        KeyCode::Up => self.move_cursor_up(),
+                        KeyCode::Down => self.move_cursor_down(),
+                        KeyCode::Home => self.column_index = 0,
+                        KeyCode::End => self.column_index = self.code.line_len(self.line_index),
+                        _ => {}
+                    },
+                    InputMode::Editing if key.kind == KeyEventKind::Press => {
+                        // Typing is grouped into one undo step; anything else,
+                        // like moving the cursor or leaving the mode, ends it.
+                        match key.code {
+                            KeyCode::Enter
+                            | KeyCode::Char(_)
+                            | KeyCode::Backspace
+                            | KeyCode::Tab => self.continue_change(),
+                            KeyCode::Esc => {
+                                self.finish_block_insert();
+                                self.commit_change();
+                            }
+                            _ => {
+                                self.block_insert = None;
+                                self.commit_change();
+                            }
+                        }
+                        match key.code {
+                            KeyCode::Enter => {
+                                self.new_line();
+                            }
+                            KeyCode::Char(to_insert) => {
+                                self.enter_char(to_insert);
+                            }
+                            KeyCode::Home => self.column_index = 0,
+                            KeyCode::End => self.column_index = self.code.line_len(self.line_index),
+                            KeyCode::Backspace if self.line_index < self.code.len_lines() => {
+                                if self.code.line_is_empty(self.line_index) && self.line_index != 0
+                                {
+                                    self.column_index = self.code.line_len(self.line_index - 1);
+                                    self.join_line(self.line_index);
+                                    self.line_index -= 1;
+                                } else if self.column_index >= self.tabstop
+                                    && (1..=self.tabstop).all(|i| {
+                                        self.code.char_at(self.line_index, self.column_index - i)
+                                            == Some(' ')
+                                    })
+                                {
+                                    for _ in 0..self.tabstop {
+                                        self.delete_char();
+                                    }
+                                } else if self.column_index == 0
+                                    && self.code.line_is_empty(self.line_index)
+                                {
+                                    if self.line_index > 0 {
+                                        self.column_index = self.code.line_len(self.line_index - 1);
+                                        self.join_line(self.line_index);
+                                        self.line_index -= 1;
+                                    }
+                                } else if self.column_index > 0 {
+                                    self.delete_char();
+                                }
+                            }
+                            KeyCode::Tab => {
+                                for _ in 0..self.tabstop {
+                                    self.enter_char(' ');
+                                }
+                            }
+                            KeyCode::Left => self.move_cursor_left(),
+                            KeyCode::Right => self.move_cursor_right(),
+                            KeyCode::Up => self.move_cursor_up(),
+                            KeyCode::Down => self.move_cursor_down(),
+                            KeyCode::Esc => self.input_mode = InputMode::Normal,
+                            _ => {}
+                        }
+                    }
+                    InputMode::Find if key.kind == KeyEventKind::Press => match key.code {
+                        KeyCode::Char('r') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            self.search_options.regex = !self.search_options.regex;
+                            self.incremental_search();
+                        }
+                        KeyCode::Char('t') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            self.search_options.next_case();
+                            self.incremental_search();
+                        }
+                        KeyCode::Char(to_find) => {
+                            self.find_str.push(to_find);
+                            self.incremental_search();
+                        }
+                        KeyCode::Backspace => {
+                            self.find_str.pop();
+                            self.incremental_search();
+                        }
+                        // Enter keeps the pattern for `n` and `N`, ESC cancels the search.
+                        KeyCode::Enter => self.input_mode = InputMode::Normal,
+                        KeyCode::Esc => {
+                            (self.line_index, self.column_index) = self.search_origin;
+                            self.find_str.clear();
+                            self.search_state = None;
+                            self.input_mode = InputMode::Normal;
+                        }
+                        KeyCode::Left => self.move_cursor_left(),
+                        KeyCode::Right => self.move_cursor_right(),
+                        KeyCode::Up => self.move_cursor_up(),
+                        KeyCode::Down => self.move_cursor_down(),
+
+                        _ => {}
+                    },
+                    InputMode::Select(kind) if key.kind == KeyEventKind::Press => {
+                        // Kept after leaving the mode, for the `'<,'>` range.
+                        self.last_selection = Some(self.selected_lines());
+                        match key.code {
+                            KeyCode::Left => self.move_cursor_left(),
+                            KeyCode::Right => self.move_cursor_right(),
+                            KeyCode::Up => self.move_cursor_up(),
+                            KeyCode::Down => self.move_cursor_down(),
+                            KeyCode::Home => self.column_index = 0,
+                            KeyCode::End => self.column_index = self.code.line_len(self.line_index),
+                            KeyCode::Char('v') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                                self.input_mode = InputMode::Select(SelectKind::Block);
+                            }
+                            KeyCode::Char('v') => {
+                                self.input_mode = InputMode::Select(SelectKind::Char)
+                            }
+                            KeyCode::Char('V') => {
+                                self.input_mode = InputMode::Select(SelectKind::Line)
+                            }
+                            KeyCode::Char(
+                                'd' | 'x' | 'c' | 'I' | 'A' | '>' | '<' | '~' | 'u' | 'U' | 'S'
+                                | '!',
+                            )
+                            | KeyCode::Delete
+                                if self.is_read_only() =>
+                            {
+                                self.refuse_edit();
+                                self.input_mode = InputMode::Normal;
+                            }
+                            KeyCode::Char('"') => self.pending_register = true,
+                            KeyCode::Char('y') => {
+                                self.yank_selection(kind);
+                                self.input_mode = InputMode::Normal;
+                            }
+                            KeyCode::Char('d') | KeyCode::Char('x') | KeyCode::Delete => {
+                                self.begin_change();
+                                self.delete_selection(kind);
+                                self.commit_change();
+                                self.input_mode = InputMode::Normal;
+                            }
+                            // Changes are left open so the text typed next joins
+                            // the same undo step.
+                            KeyCode::Char('c') => {
+                                self.begin_change();
+                                self.typed = false;
+                                self.change_selection(kind);
+                                self.input_mode = InputMode::Editing;
+                            }
+                            KeyCode::Char('I') | KeyCode::Char('A')
+                                if matches!(kind, SelectKind::Block) =>
+                            {
+                                let lines = self.selected_lines();
+                                let columns = self.block_columns();
+                                self.begin_change();
+                                self.typed = false;
+                                if key.code == KeyCode::Char('I') {
+                                    self.block_insert(lines, columns.start, false);
+                                } else {
+                                    self.block_insert(lines, columns.end, true);
+                                }
+                                self.input_mode = InputMode::Editing;
+                            }
+                            KeyCode::Char('>') | KeyCode::Char('<') => {
+                                self.begin_change();
+                                self.indent_lines(
+                                    self.selected_lines(),
+                                    key.code == KeyCode::Char('<'),
+                                );
+                                self.commit_change();
+                                self.input_mode = InputMode::Normal;
+                            }
+                            KeyCode::Char('~') | KeyCode::Char('u') | KeyCode::Char('U') => {
+                                let change_case: fn(char) -> String = match key.code {
+                                    KeyCode::Char('u') => |c| c.to_lowercase().collect(),
+                                    KeyCode::Char('U') => |c| c.to_uppercase().collect(),
+                                    _ => |c| {
+                                        if c.is_lowercase() {
+                                            c.to_uppercase().collect()
+                                        } else {
+                                            c.to_lowercase().collect()
+                                        }
+                                    },
+                                };
+                                self.begin_change();
+                                self.change_case_selection(kind, change_case);
+                                self.commit_change();
+                                self.input_mode = InputMode::Normal;
+                            }
+                            // Substitutes in the selected lines, like `:'<,'>s` in vi.
+                            KeyCode::Char('S') => {
+                                self.substitute_str = "'<,'>".to_string();
+                                self.input_mode = InputMode::Substitute;
+                            }
+                            KeyCode::Char(':') => {
+                                self.command_line.start("'<,'>");
+                                self.input_mode = InputMode::Command;
+                            }
+                            KeyCode::Char('!') => {
+                                self.command_line.start("'<,'>!");
+                                self.input_mode = InputMode::Command;
+                            }
+                            KeyCode::Esc => self.input_mode = InputMode::Normal,
+                            _ => {}
+                        }
+                    }
+                    InputMode::Substitute
+                        if key.kind == KeyEventKind::Press && self.substitution.is_some() =>
+                    {
+                        match key.code {
+                            KeyCode::Char(answer @ ('y' | 'n' | 'a' | 'q')) => {
+                                self.answer_substitution(answer)
+                            }
+                            KeyCode::Esc => self.answer_substitution('q'),
+                            _ => {}
+                        }
+                    }
+                    InputMode::Substitute if key.kind == KeyEventKind::Press => match key.code {
+                        KeyCode::Char(c) => self.substitute_str.push(c),
+                        KeyCode::Backspace => {
+                            self.substitute_str.pop();
+                        }
+                        KeyCode::Enter => self.substitute(),
+                        KeyCode::Esc => self.input_mode = InputMode::Normal,
+                        _ => {}
+                    },
+                    InputMode::Command if key.kind == KeyEventKind::Press => match key.code {
+                        KeyCode::Char(c) => self.command_line.push(c),
+                        KeyCode::Backspace if self.command_line.text.is_empty() => {
+                            self.input_mode = InputMode::Normal;
+                        }
+                        KeyCode::Backspace => self.command_line.pop(),
+                        KeyCode::Tab => self.command_line.complete(),
+                        KeyCode::Up => self.command_line.history_previous(),
+                        KeyCode::Down => self.command_line.history_next(),
+                        KeyCode::Enter if self.execute_command() => return Ok(()),
+                        KeyCode::Esc => self.input_mode = InputMode::Normal,
+                        _ => {}
+                    },
+                    InputMode::Hex if key.kind == KeyEventKind::Press => {
+                        let Some(hex) = &mut self.hex else {
+                            continue;
+                        };
+                        match key.code {
+                            KeyCode::Left | KeyCode::Char('h') => hex.move_nibble(false),
+                            KeyCode::Right | KeyCode::Char('l') => hex.move_nibble(true),
+                            KeyCode::Up | KeyCode::Char('k') => {
+                                hex.move_by(-(BYTES_PER_ROW as isize))
+                            }
+                            KeyCode::Down | KeyCode::Char('j') => {
+                                hex.move_by(BYTES_PER_ROW as isize)
+                            }
+                            KeyCode::Home => hex.row_start(),
+                            KeyCode::End => hex.row_end(),
+                            KeyCode::Char(c) if c.is_ascii_hexdigit() => {
+                                hex.set_nibble(c.to_digit(16).unwrap_or(0) as u8);
+                            }
+                            KeyCode::Char('u') if !hex.undo() => {
+                                self.message = Some(" Nothing to undo ".to_string());
+                            }
+                            KeyCode::Char('s') => self.save_hex(),
+                            KeyCode::Esc => self.close_hex(),
+                            _ => {}
+                        }
+                    }
+                    InputMode::Quit if key.kind == KeyEventKind::Press => match key.code {
+                        KeyCode::Char('s') | KeyCode::Char('y') => {
+                            if self.save_file() {
+                                return Ok(());
+                            }
+                            self.input_mode = InputMode::Normal;
+                        }
+                        KeyCode::Char('d') | KeyCode::Char('n') => return Ok(()),
+                        KeyCode::Char('c') | KeyCode::Esc => self.input_mode = InputMode::Normal,
+                        _ => {}
+                    },
+                    InputMode::Grep if key.kind == KeyEventKind::Press => match key.code {
+                        KeyCode::Char('r') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            self.search_options.regex = !self.search_options.regex;
+                        }
+                        KeyCode::Char('t') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            self.search_options.next_case();
+                        }
+                        KeyCode::Char(c) => self.grep_str.push(c),
+                        KeyCode::Backspace => {
+                            self.grep_str.pop();
+                        }
+                        KeyCode::Enter if !self.grep_str.is_empty() => self.run_grep(),
+                        KeyCode::Esc => self.input_mode = InputMode::Normal,
+                        _ => {}
+                    },
+                    InputMode::GrepResults if key.kind == KeyEventKind::Press => match key.code {
+                        KeyCode::Up => self.grep_index = self.grep_index.saturating_sub(1),
+                        KeyCode::Down if self.grep_index + 1 < self.grep_hits.len() => {
+                            self.grep_index += 1;
+                        }
+                        KeyCode::Enter => self.open_hit(),
+                        KeyCode::Esc => self.input_mode = InputMode::Normal,
+                        _ => {}
+                    },
+                    InputMode::UndoTree if key.kind == KeyEventKind::Press => match key.code {
+                        KeyCode::Up => {
+                            self.undo_tree_index = self.undo_tree_index.saturating_sub(1);
+                        }
+                        KeyCode::Down if self.undo_tree_index + 1 < self.history.len() => {
+                            self.undo_tree_index += 1;
+                        }
+                        KeyCode::Enter => {
+                            self.undo_goto(self.undo_tree_index);
+                            self.input_mode = InputMode::Normal;
+                        }
+                        KeyCode::Esc => self.input_mode = InputMode::Normal,
+                        _ => {}
+                    },
+                    InputMode::Editing => {}
+                    InputMode::Find => {}
+                    InputMode::Select(_) => {}
+                    InputMode::UndoTree => {}
+                    InputMode::Substitute => {}
+                    InputMode::Grep => {}
+                    InputMode::GrepResults => {}
+                    InputMode::Command => {}
+                    InputMode::Quit => {}
+                    InputMode::Hex => {}
+                }
+            }
+        }
+    }
+
+    pub fn normal_info_text(&mut self) {
+        let modified = if self.is_modified() { " [+]" } else { "" };
+        let format = match self.file_format.label() {
+            label if label.is_empty() => label,
+            label => format!(" [{label}]"),
+        };
+        self.info_text = format!(
+            "  <{}>{modified}{format} - edit: i, save: s, find: /, undo-redo: u-r, yank-put: y-p, quit: q ",
+            self.save_path
+        );
+        if !self.find_str.is_empty() {
+            let counter = self.match_counter();
+            self.info_text.push_str(&format!("- {counter} "));
+        }
+        if let Some(name) = self.register_name {
+            self.info_text.push_str(&format!("- register: \"{name} "));
+        }
+    }
+
+    pub fn edit_info_text(&mut self) {
+        self.info_text = format!(
+            " <{}> - x:{}|y:{} - quit: ESC ",
+            self.save_path, self.column_index, self.line_index,
+        );
+    }
+
+    pub fn find_info_text(&mut self) {
+        let counter = self.match_counter();
+        self.info_text = format!(
+            " Search in <{} for quit: ESC> ({}, toggle: ^R ^T) : {} - {counter} ",
+            self.save_path,
+            self.search_options.label(),
+            self.find_str
+        );
+    }
+
+    pub fn substitute_info_text(&mut self) {
+        self.info_text = match &self.substitution {
+            Some(substitution) => {
+                let text = substitution
+                    .current()
+                    .map(|(_, text)| text)
+                    .unwrap_or_default();
+                format!(
+                    " Replace with \"{}\"? yes: y, no: n, all: a, quit: q - {} replaced ",
+                    text.replace('\n', "\\n"),
+                    substitution.count
+                )
+            }
+            None => format!(
+                " Substitute in <{} for quit: ESC> ([range]s/pattern/replacement/[gcIi]) : {} ",
+                self.save_path, self.substitute_str
+            ),
+        };
+    }
+
+    pub fn quit_info_text(&mut self) {
+        self.info_text = format!(
+            " <{}> has unsaved changes - save and quit: s, discard and quit: d, cancel: c/ESC ",
+            self.save_path
+        );
+    }
+
+    pub fn command_info_text(&mut self) {
+        self.info_text = format!(" :{} ", self.command_line.text);
+    }
+
+    pub fn grep_info_text(&mut self) {
+        self.info_text = format!(
+            " Grep in files under the working directory for quit: ESC ({}, toggle: ^R ^T) : {} ",
+            self.search_options.label(),
+            self.grep_str
+        );
+    }
+
+    pub fn grep_results_info_text(&mut self) {
+        self.info_text = format!(
+            " {} - hit {}/{}, open: Enter, quit: ESC ",
+            self.grep_str,
+            self.grep_index + 1,
+            self.grep_hits.len()
+        );
+        if let Some(message) = &self.message {
+            self.info_text.push_str(&format!("-{message}"));
+        }
+    }
+
+    pub fn hex_info_text(&mut self) {
+        let Some(hex) = &self.hex else {
+            return;
+        };
+        let modified = if hex.is_modified() { " [+]" } else { "" };
+        let byte = match hex.bytes.get(hex.cursor) {
+            Some(byte) => format!("{byte:02x} ({byte})"),
+            None => "empty".to_string(),
+        };
+        self.info_text = format!(
+            " <{}>{modified} - offset {:08x}: {byte} - overwrite: 0-f, undo: u, save: s, quit: ESC ",
+            self.save_path, hex.cursor
+        );
+    }
+
+    pub fn save_info_text(&mut self) {
+        self.info_text = format!(" File saved to <{}>", self.save_path);
+    }
+
+    pub fn undo_tree_info_text(&mut self) {
+        self.info_text = format!(
+            " Undo tree of <{}> - state {}, jump: Enter, quit: ESC ",
+            self.save_path,
+            self.history.current()
+        );
+    }
+
+    pub fn select_info_text(&mut self, kind: SelectKind) {
+        let lines = self.selected_lines();
+        self.info_text = format!(
+            "Selection x:{}, y:{}, {} lines, <{}>",
+            self.column_index,
+            self.line_index,
+            lines.end() - lines.start() + 1,
+            self.save_path
+        );
+        if let SelectKind::Block = kind {
+            self.info_text.push_str(" - insert: I, append: A");
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let vertical = Layout::vertical([Constraint::Length(1), Constraint::Min(1)]);
+        let [status_area, edit_area] = vertical.areas(frame.area());
+        // let helped_layout = Layout::vertical([
+        // Constraint::Length(1),
+        // Constraint::Length(2),
+        // Constraint::Min(1),
+        // ]);
+
+        match self.input_mode {
+            InputMode::Normal => {
+                if let Some(message) = &self.message {
+                    self.info_text = message.clone();
+                } else if self.saved && !self.is_modified() {
+                    self.save_info_text();
+                } else {
+                    self.normal_info_text();
+                }
+            }
+            InputMode::Editing => self.edit_info_text(),
+            InputMode::Find => self.find_info_text(),
+            InputMode::Select(kind) => self.select_info_text(kind),
+            InputMode::UndoTree => self.undo_tree_info_text(),
+            InputMode::Substitute => self.substitute_info_text(),
+            InputMode::Grep => self.grep_info_text(),
+            InputMode::GrepResults => self.grep_results_info_text(),
+            InputMode::Command => self.command_info_text(),
+            InputMode::Quit => self.quit_info_text(),
+            InputMode::Hex => match &self.message {
+                Some(message) => self.info_text = message.clone(),
+                None => self.hex_info_text(),
+            },
+        }
+
+        let (msg, style) = match self.input_mode {
+            InputMode::Normal => (
+                vec![
+                    " Normal ".bg(Color::Yellow),
+                    "".bg(Color::Gray).fg(Color::Yellow),
+                    "".fg(Color::Gray).bg(Color::DarkGray),
                     self.info_text
                         .to_string()
                         .fg(Color::White)
@@ -540,9 +2275,14 @@ impl App {
                 ],
                 Style::default().fg(Color::Black),
             ),
-            InputMode::Select => (
+            InputMode::Select(kind) => (
                 vec![
-                    " Select ".bg(Color::Green),
+                    match kind {
+                        SelectKind::Char => " Select ",
+                        SelectKind::Line => " Select Line ",
+                        SelectKind::Block => " Select Block ",
+                    }
+                    .bg(Color::Green),
                     "".bg(Color::Gray).fg(Color::Green),
                     "".fg(Color::Gray).bg(Color::DarkGray),
                     self.info_text
@@ -553,6 +2293,84 @@ impl App {
                 ],
                 Style::default().fg(Color::Black),
             ),
+            InputMode::UndoTree => (
+                vec![
+                    " Undo ".bg(Color::Magenta),
+                    "".bg(Color::Gray).fg(Color::Magenta),
+                    "".fg(Color::Gray).bg(Color::DarkGray),
+                    self.info_text
+                        .to_string()
+                        .fg(Color::White)
+                        .bg(Color::DarkGray),
+                    "".fg(Color::DarkGray),
+                ],
+                Style::default().fg(Color::Black),
+            ),
+            InputMode::Quit => (
+                vec![
+                    " Quit ".bg(Color::LightRed),
+                    "".bg(Color::Gray).fg(Color::LightRed),
+                    "".fg(Color::Gray).bg(Color::DarkGray),
+                    self.info_text
+                        .to_string()
+                        .fg(Color::White)
+                        .bg(Color::DarkGray),
+                    "".fg(Color::DarkGray),
+                ],
+                Style::default().fg(Color::Black),
+            ),
+            InputMode::Hex => (
+                vec![
+                    " Hex ".bg(Color::Blue),
+                    "".bg(Color::Gray).fg(Color::Blue),
+                    "".fg(Color::Gray).bg(Color::DarkGray),
+                    self.info_text
+                        .to_string()
+                        .fg(Color::White)
+                        .bg(Color::DarkGray),
+                    "".fg(Color::DarkGray),
+                ],
+                Style::default().fg(Color::Black),
+            ),
+            InputMode::Command => (
+                vec![
+                    " Command ".bg(Color::LightYellow),
+                    "".bg(Color::Gray).fg(Color::LightYellow),
+                    "".fg(Color::Gray).bg(Color::DarkGray),
+                    self.info_text
+                        .to_string()
+                        .fg(Color::White)
+                        .bg(Color::DarkGray),
+                    "".fg(Color::DarkGray),
+                ],
+                Style::default().fg(Color::Black),
+            ),
+            InputMode::Grep | InputMode::GrepResults => (
+                vec![
+                    " Grep ".bg(Color::LightRed),
+                    "".bg(Color::Gray).fg(Color::LightRed),
+                    "".fg(Color::Gray).bg(Color::DarkGray),
+                    self.info_text
+                        .to_string()
+                        .fg(Color::White)
+                        .bg(Color::DarkGray),
+                    "".fg(Color::DarkGray),
+                ],
+                Style::default().fg(Color::Black),
+            ),
+            InputMode::Substitute => (
+                vec![
+                    " Substitute ".bg(Color::Cyan),
+                    "".bg(Color::Gray).fg(Color::Cyan),
+                    "".fg(Color::Gray).bg(Color::DarkGray),
+                    self.info_text
+                        .to_string()
+                        .fg(Color::White)
+                        .bg(Color::DarkGray),
+                    "".fg(Color::DarkGray),
+                ],
+                Style::default().fg(Color::Black),
+            ),
         };
 
         let status_bar_text = Text::from(Line::from(msg)).patch_style(style);
@@ -560,26 +2378,83 @@ impl App {
 
         frame.render_widget(status_bar, status_area);
 
-        let width = self.code.len().to_string().len();
+        if let InputMode::UndoTree = self.input_mode {
+            self.draw_undo_tree(frame, edit_area);
+            return;
+        }
+        if let InputMode::GrepResults = self.input_mode {
+            self.draw_grep_results(frame, edit_area);
+            return;
+        }
+        if let InputMode::Hex = self.input_mode {
+            self.draw_hex(frame, edit_area);
+            return;
+        }
+
+        let width = self.code.len_lines().to_string().len();
+        self.scroll_to(self.line_index, self.column_index, edit_area);
+
+        // Only the lines on screen are rendered, so drawing cost does not
+        // grow with the size of the file.
+        let visible_lines = self.scroll_ofst
+            ..self
+                .code
+                .len_lines()
+                .min(self.scroll_ofst + edit_area.height as usize);
         let text_lines: Vec<Line> = match self.input_mode {
-            InputMode::Normal | InputMode::Editing => {
-                self.find_str.clear();
-                self.code
-                    .iter()
-                    .enumerate()
-                    .map(|(i, code_line)| syntax_highln(format!("{i:>width$} {code_line}")))
+            InputMode::Normal | InputMode::Editing => visible_lines
+                .map(|i| {
+                    let code_line = self.code.line(i);
+                    syntax_highln(format!("{i:>width$} {code_line}"))
+                })
+                .collect(),
+            InputMode::Find => {
+                self.refresh_matches();
+                visible_lines
+                    .map(|i| {
+                        let code_line = self.code.line(i);
+                        let line = syntax_highln(format!("{i:>width$} {code_line}"));
+                        let matches = search::line_matches(&self.search_matches, i);
+                        find_impl(line, matches, width + 1)
+                    })
                     .collect()
             }
-            InputMode::Find => self
-                .code
-                .iter()
-                .map(|line| find_impl(line.to_string(), self.find_str.clone()))
+            InputMode::Select(kind) => visible_lines
+                .map(|i| {
+                    let (startp, endp) = self.selection_columns(kind, i);
+                    let mut line = select_impl(self.code.line_string(i), startp, endp);
+                    line.spans.insert(0, Span::raw(format!("{i:>width$} ")));
+                    line
+                })
                 .collect(),
-            InputMode::Select => self
-                .code
-                .iter()
-                .map(|line| find_impl(line.to_string(), self.find_str.clone()))
+            InputMode::Substitute => {
+                let current = self.substitution.as_ref().and_then(Confirm::current);
+                let found = current.map(|(range, _)| {
+                    let (line, start) = self.code.char_to_pos(range.start);
+                    Match {
+                        line,
+                        start,
+                        end: start + range.len(),
+                    }
+                });
+                visible_lines
+                    .map(|i| {
+                        let code_line = self.code.line(i);
+                        let line = syntax_highln(format!("{i:>width$} {code_line}"));
+                        match found {
+                            Some(found) if found.line == i => find_impl(line, &[found], width + 1),
+                            _ => line,
+                        }
+                    })
+                    .collect()
+            }
+            InputMode::Grep | InputMode::Command | InputMode::Quit => visible_lines
+                .map(|i| {
+                    let code_line = self.code.line(i);
+                    syntax_highln(format!("{i:>width$} {code_line}"))
+                })
                 .collect(),
+            InputMode::UndoTree | InputMode::GrepResults | InputMode::Hex => Vec::new(),
         };
 
         let text = Text::from(text_lines);
@@ -587,12 +2462,27 @@ impl App {
             InputMode::Normal => Style::default().fg(Color::Gray),
             InputMode::Editing => Style::default().fg(Color::White),
             InputMode::Find => Style::default().fg(Color::White),
-            InputMode::Select => Style::default().fg(Color::White),
+            InputMode::Select(_) => Style::default().fg(Color::White),
+            InputMode::UndoTree => Style::default().fg(Color::White),
+            InputMode::Substitute => Style::default().fg(Color::White),
+            InputMode::Grep | InputMode::GrepResults => Style::default().fg(Color::Gray),
+            InputMode::Command | InputMode::Quit => Style::default().fg(Color::Gray),
+            InputMode::Hex => Style::default().fg(Color::White),
         });
-        let visible_height = edit_area.height.saturating_sub(1) as usize;
-        let crsrl = self.line_index;
+        input = input.scroll((0, self.scroll_hofst as u16));
+        frame.render_widget(input, edit_area);
+        frame.set_cursor_position(Position::new(
+            edit_area.x + self.column_index as u16 + width as u16 + 1,
+            edit_area.y + (self.line_index - self.scroll_ofst) as u16,
+        ));
+    }
+
+    /// Scrolls `area` so that `line` and `column` are on screen.
+    fn scroll_to(&mut self, line: usize, column: usize, area: Rect) {
+        let visible_height = area.height.saturating_sub(1) as usize;
+        let crsrl = line;
         let sheight = visible_height;
-        let swidth = edit_area.width as usize;
+        let swidth = area.width as usize;
         let stop = self.scroll_ofst;
         let sbottom = self.scroll_ofst + sheight.saturating_sub(1);
 
@@ -602,17 +2492,69 @@ impl App {
             self.scroll_ofst = crsrl;
         }
 
-        if self.column_index >= self.scroll_hofst + swidth {
-            self.scroll_hofst = self.column_index.saturating_sub(swidth).saturating_add(1);
-        } else if self.column_index < self.scroll_hofst {
-            self.scroll_hofst = self.column_index;
+        if column >= self.scroll_hofst + swidth {
+            self.scroll_hofst = column.saturating_sub(swidth).saturating_add(1);
+        } else if column < self.scroll_hofst {
+            self.scroll_hofst = column;
         }
+    }
 
-        input = input.scroll((self.scroll_ofst as u16, self.scroll_hofst as u16));
-        frame.render_widget(input, edit_area);
+    fn draw_hex(&mut self, frame: &mut Frame, area: Rect) {
+        let Some(hex) = &self.hex else {
+            return;
+        };
+        let row = hex.cursor_row();
+        let column = hex::hex_column(hex.cursor) + hex.low_nibble as usize;
+        let len_rows = hex.len_rows();
+        self.scroll_to(row, column, area);
+        let Some(hex) = &self.hex else {
+            return;
+        };
+        // As with text, only the rows on screen are rendered.
+        let visible_rows = self.scroll_ofst..len_rows.min(self.scroll_ofst + area.height as usize);
+        let lines: Vec<Line> = visible_rows.map(|row| hex_impl(hex, row)).collect();
+        let view = Paragraph::new(Text::from(lines))
+            .style(Style::default().fg(Color::White))
+            .scroll((0, self.scroll_hofst as u16));
+        frame.render_widget(view, area);
         frame.set_cursor_position(Position::new(
-            edit_area.x + self.column_index as u16 + width as u16 + 1,
-            edit_area.y + (self.line_index - self.scroll_ofst) as u16,
+            area.x + (column - self.scroll_hofst) as u16,
+            area.y + (row - self.scroll_ofst) as u16,
         ));
     }
+
+    fn draw_undo_tree(&mut self, frame: &mut Frame, area: Rect) {
+        let height = area.height as usize;
+        let first = self
+            .undo_tree_index
+            .saturating_sub(height.saturating_sub(1));
+        let current = self.history.current();
+        let lines: Vec<Line> = self
+            .history
+            .entries()
+            .skip(first)
+            .take(height)
+            .map(|entry| {
+                let selected = entry.seq == self.undo_tree_index;
+                undo_tree_impl(entry, current, selected)
+            })
+            .collect();
+        let list = Paragraph::new(Text::from(lines)).style(Style::default().fg(Color::White));
+        frame.render_widget(list, area);
+    }
+
+    fn draw_grep_results(&mut self, frame: &mut Frame, area: Rect) {
+        let height = area.height as usize;
+        let first = self.grep_index.saturating_sub(height.saturating_sub(1));
+        let lines: Vec<Line> = self
+            .grep_hits
+            .iter()
+            .enumerate()
+            .skip(first)
+            .take(height)
+            .map(|(index, hit)| grep_impl(hit, index == self.grep_index))
+            .collect();
+        let list = Paragraph::new(Text::from(lines)).style(Style::default().fg(Color::White));
+        frame.render_widget(list, area);
+    }
 }
@@ -0,0 +1,212 @@
+use crate::{
+    buffer::Buffer,
+    search::{self, SearchOptions},
+};
+use std::ops::RangeInclusive;
+
+/// Where an address starts counting from.
+#[derive(Clone)]
+enum Base {
+    /// `N`, a line number as shown in the gutter.
+    Number(usize),
+    /// `.`
+    Current,
+    /// `$`
+    Last,
+    /// `/pattern/` searches forward from the line after the cursor and
+    /// `?pattern?` backward from the line before it, wrapping around.
+    Search { pattern: String, forward: bool },
+    /// `'<`
+    SelectionStart,
+    /// `'>`
+    SelectionEnd,
+}
+
+/// A line address: a base and `+N` / `-N` offsets, e.g. `.+3` or `$-1`.
+#[derive(Clone)]
+struct Address {
+    base: Base,
+    offset: isize,
+}
+
+/// A range of lines given as two addresses, like `10,20` or `.,$`. A
+/// single address stands for a range of one line.
+#[derive(Clone)]
+pub struct LineRange {
+    start: Address,
+    end: Address,
+}
+
+/// What addresses are resolved against.
+pub struct Context<'a> {
+    pub buffer: &'a Buffer,
+    pub cursor_line: usize,
+    /// Lines of the last selection, for `'<` and `'>`.
+    pub selection: Option<RangeInclusive<usize>>,
+    pub search_options: SearchOptions,
+}
+
+/// Parses the range at the start of a command line, returning it with the
+/// rest of the line. `%` is the whole file.
+pub fn parse(text: &str) -> Result<(Option<LineRange>, &str), String> {
+    let mut rest = text.trim_start();
+    if let Some(after) = rest.strip_prefix('%') {
+        let range = LineRange {
+            start: Address {
+                base: Base::Number(0),
+                offset: 0,
+            },
+            end: Address {
+                base: Base::Last,
+                offset: 0,
+            },
+        };
+        return Ok((Some(range), after.trim_start()));
+    }
+    let start = parse_address(&mut rest)?;
+    let range = match rest.strip_prefix(',') {
+        Some(after) => {
+            rest = after;
+            let current = || Address {
+                base: Base::Current,
+                offset: 0,
+            };
+            let start = start.unwrap_or_else(current);
+            let end = parse_address(&mut rest)?.unwrap_or_else(current);
+            Some(LineRange { start, end })
+        }
+        None => start.map(|start| LineRange {
+            end: start.clone(),
+            start,
+        }),
+    };
+    Ok((range, rest.trim_start()))
+}
+
+fn parse_address(rest: &mut &str) -> Result<Option<Address>, String> {
+    let text = *rest;
+    let digits = text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let mut chars = text.chars();
+    let base = match chars.next() {
+        Some('0'..='9') => {
+            let number = text[..digits]
+                .parse()
+                .map_err(|_| format!("invalid line number `{}`", &text[..digits]))?;
+            *rest = &text[digits..];
+            Base::Number(number)
+        }
+        Some('.') => {
+            *rest = &text[1..];
+            Base::Current
+        }
+        Some('$') => {
+            *rest = &text[1..];
+            Base::Last
+        }
+        Some(delimiter @ ('/' | '?')) => {
+            let mut pattern = String::new();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => match chars.next() {
+                        Some(next) if next == delimiter => pattern.push(next),
+                        Some(next) => {
+                            pattern.push('\\');
+                            pattern.push(next);
+                        }
+                        None => pattern.push('\\'),
+                    },
+                    c if c == delimiter => break,
+                    c => pattern.push(c),
+                }
+            }
+            if pattern.is_empty() {
+                return Err("empty pattern".to_string());
+            }
+            *rest = chars.as_str();
+            Base::Search {
+                pattern,
+                forward: delimiter == '/',
+            }
+        }
+        Some('\'') => {
+            let base = match chars.next() {
+                Some('<') => Base::SelectionStart,
+                Some('>') => Base::SelectionEnd,
+                _ => return Err("unknown mark".to_string()),
+            };
+            *rest = &text[2..];
+            base
+        }
+        Some('+' | '-') => Base::Current,
+        _ => return Ok(None),
+    };
+
+    let mut offset: isize = 0;
+    while let Some(sign) = rest.chars().next().filter(|c| *c == '+' || *c == '-') {
+        let after = &rest[1..];
+        let digits = after.len() - after.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let count: isize = match digits {
+            0 => 1,
+            _ => after[..digits]
+                .parse()
+                .map_err(|_| "invalid offset".to_string())?,
+        };
+        offset += if sign == '+' { count } else { -count };
+        *rest = &after[digits..];
+    }
+    Ok(Some(Address { base, offset }))
+}
+
+impl Address {
+    fn resolve(&self, context: &Context) -> Result<usize, String> {
+        let last = context.buffer.len_lines() - 1;
+        let line = match &self.base {
+            Base::Number(line) => *line,
+            Base::Current => context.cursor_line,
+            Base::Last => last,
+            Base::Search { pattern, forward } => search_line(context, pattern, *forward)?,
+            Base::SelectionStart | Base::SelectionEnd => {
+                let selection = context.selection.as_ref().ok_or("no selection")?;
+                match self.base {
+                    Base::SelectionStart => *selection.start(),
+                    _ => *selection.end(),
+                }
+            }
+        };
+        match line.checked_add_signed(self.offset) {
+            Some(line) if line <= last => Ok(line),
+            _ => Err("line out of range".to_string()),
+        }
+    }
+}
+
+/// The first line after (or before) the cursor matching `pattern`.
+fn search_line(context: &Context, pattern: &str, forward: bool) -> Result<usize, String> {
+    let options = SearchOptions {
+        regex: true,
+        ..context.search_options
+    };
+    let regex = search::compile(pattern, options).map_err(|_| "invalid pattern".to_string())?;
+    let len_lines = context.buffer.len_lines();
+    (1..=len_lines)
+        .map(|step| {
+            if forward {
+                (context.cursor_line + step) % len_lines
+            } else {
+                (context.cursor_line + len_lines - step) % len_lines
+            }
+        })
+        .find(|&line| regex.is_match(&context.buffer.line_string(line)))
+        .ok_or_else(|| format!("pattern not found: {pattern}"))
+}
+
+impl LineRange {
+    pub fn resolve(&self, context: &Context) -> Result<RangeInclusive<usize>, String> {
+        let start = self.start.resolve(context)?;
+        let end = self.end.resolve(context)?;
+        if start > end {
+            return Err("backwards range".to_string());
+        }
+        Ok(start..=end)
+    }
+}
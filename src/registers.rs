@@ -0,0 +1,73 @@
+use std::collections::{HashMap, VecDeque};
+
+/// Text held in a register. Line-wise text is put on lines of its own
+/// instead of at the cursor.
+#[derive(Clone)]
+pub struct Register {
+    pub text: String,
+    pub linewise: bool,
+}
+
+/// The unnamed register, `0` for the last yank, `1`-`9` for the last
+/// deletes, newest first, and the named registers `a`-`z`. Naming a
+/// register in upper case appends to it.
+#[derive(Default)]
+pub struct Registers {
+    unnamed: Option<Register>,
+    yanked: Option<Register>,
+    deleted: VecDeque<Register>,
+    named: HashMap<char, Register>,
+}
+
+impl Registers {
+    pub fn is_valid_name(name: char) -> bool {
+        name == '"' || name == '+' || name.is_ascii_digit() || name.is_ascii_alphabetic()
+    }
+
+    pub fn get(&self, name: Option<char>) -> Option<&Register> {
+        match name {
+            None | Some('"') => self.unnamed.as_ref(),
+            Some('0') => self.yanked.as_ref(),
+            Some(digit @ '1'..='9') => self.deleted.get(digit as usize - '1' as usize),
+            Some(name) => self.named.get(&name.to_ascii_lowercase()),
+        }
+    }
+
+    pub fn yank(&mut self, name: Option<char>, register: Register) {
+        if !self.store_named(name, &register) {
+            self.yanked = Some(register.clone());
+            self.unnamed = Some(register);
+        }
+    }
+
+    pub fn delete(&mut self, name: Option<char>, register: Register) {
+        if !self.store_named(name, &register) {
+            self.deleted.push_front(register.clone());
+            self.deleted.truncate(9);
+            self.unnamed = Some(register);
+        }
+    }
+
+    /// Stores into a named register, if one was given. The unnamed register
+    /// always points at the result.
+    fn store_named(&mut self, name: Option<char>, register: &Register) -> bool {
+        let Some(name) = name.filter(char::is_ascii_alphabetic) else {
+            return false;
+        };
+        let key = name.to_ascii_lowercase();
+        let stored = match self.named.get(&key) {
+            Some(existing) if name.is_ascii_uppercase() => {
+                let linewise = existing.linewise || register.linewise;
+                let separator = if linewise { "\n" } else { "" };
+                Register {
+                    text: format!("{}{separator}{}", existing.text, register.text),
+                    linewise,
+                }
+            }
+            _ => register.clone(),
+        };
+        self.unnamed = Some(stored.clone());
+        self.named.insert(key, stored);
+        true
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    process,
+};
+
+/// Replaces the file at `path` with what `write` writes, so that a crash
+/// or a full disk leaves either the old file or the new one, never a
+/// truncated mix.
+///
+/// The text goes to a temporary file in the same directory, which is
+/// synced to disk and then renamed over the original. The original's
+/// permissions and, where allowed, its owner carry over to the new file.
+/// Symbolic links are followed, so the link itself stays in place.
+pub fn write_atomic(
+    path: &Path,
+    write: impl FnOnce(&mut File) -> io::Result<()>,
+) -> io::Result<()> {
+    replace(path, false, write)
+}
+
+/// Like `write_atomic`, but the file is only readable by its owner,
+/// whatever the permissions of the file it replaces. Used for copies of
+/// unsaved text, which may come from a file others cannot read.
+pub fn write_private(
+    path: &Path,
+    write: impl FnOnce(&mut File) -> io::Result<()>,
+) -> io::Result<()> {
+    replace(path, true, write)
+}
+
+fn replace(
+    path: &Path,
+    private: bool,
+    write: impl FnOnce(&mut File) -> io::Result<()>,
+) -> io::Result<()> {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file name"))?;
+    let temp_path = dir.join(format!(
+        ".{}.edfi-{}.tmp",
+        name.to_string_lossy(),
+        process::id()
+    ));
+
+    let result =
+        write_temp(&path, &temp_path, private, write).and_then(|()| fs::rename(&temp_path, &path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+        return result;
+    }
+    // Syncing the directory makes the rename itself durable. Not every
+    // platform can open a directory for this, so it is best-effort.
+    if let Ok(dir) = File::open(&dir) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+fn write_temp(
+    path: &Path,
+    temp_path: &Path,
+    private: bool,
+    write: impl FnOnce(&mut File) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(temp_path)?;
+    let metadata = fs::metadata(path).ok().filter(|_| !private);
+    if let Some(metadata) = metadata {
+        file.set_permissions(metadata.permissions())?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{fchown, MetadataExt};
+            // Only root can give a file away, so failing to keep another
+            // user's ownership is not an error.
+            let _ = fchown(&file, Some(metadata.uid()), Some(metadata.gid()));
+        }
+    }
+    write(&mut file)?;
+    file.sync_all()
+}
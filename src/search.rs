@@ -0,0 +1,134 @@
+use crate::{buffer::Buffer, history::Cursor};
+use regex::{Regex, RegexBuilder};
+
+/// A match of the search pattern, as char columns `start..end` of `line`.
+#[derive(Clone, Copy)]
+pub struct Match {
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Match {
+    pub fn cursor(&self) -> Cursor {
+        (self.line, self.start)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum CaseMode {
+    Sensitive,
+    Insensitive,
+    /// Case-insensitive unless the pattern has an upper case letter.
+    Smart,
+}
+
+#[derive(Clone, Copy)]
+pub struct SearchOptions {
+    pub regex: bool,
+    pub case: CaseMode,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        Self {
+            regex: false,
+            case: CaseMode::Sensitive,
+        }
+    }
+}
+
+impl SearchOptions {
+    pub fn next_case(&mut self) {
+        self.case = match self.case {
+            CaseMode::Sensitive => CaseMode::Insensitive,
+            CaseMode::Insensitive => CaseMode::Smart,
+            CaseMode::Smart => CaseMode::Sensitive,
+        };
+    }
+
+    /// Short description for the status bar, e.g. `regex, smart-case`.
+    pub fn label(&self) -> String {
+        let kind = if self.regex { "regex" } else { "literal" };
+        let case = match self.case {
+            CaseMode::Sensitive => "match case",
+            CaseMode::Insensitive => "ignore case",
+            CaseMode::Smart => "smart-case",
+        };
+        format!("{kind}, {case}")
+    }
+}
+
+/// Builds the regex for a search. Literal patterns are escaped, so both
+/// kinds of search share the same matcher.
+pub fn compile(pattern: &str, options: SearchOptions) -> Result<Regex, regex::Error> {
+    let source = if options.regex {
+        pattern.to_string()
+    } else {
+        regex::escape(pattern)
+    };
+    let ignore_case = match options.case {
+        CaseMode::Sensitive => false,
+        CaseMode::Insensitive => true,
+        CaseMode::Smart => !pattern.chars().any(char::is_uppercase),
+    };
+    RegexBuilder::new(&source)
+        .case_insensitive(ignore_case)
+        .build()
+}
+
+/// Every match of `regex` in the buffer, in document order. Matches do not
+/// span lines.
+pub fn find_matches(buffer: &Buffer, regex: &Regex) -> Vec<Match> {
+    let mut matches = Vec::new();
+    for line_index in 0..buffer.len_lines() {
+        let line = buffer.line_string(line_index);
+        let mut column = 0;
+        let mut last_byte = 0;
+        for found in regex.find_iter(&line) {
+            column += line[last_byte..found.start()].chars().count();
+            let len = found.as_str().chars().count();
+            matches.push(Match {
+                line: line_index,
+                start: column,
+                end: column + len,
+            });
+            column += len;
+            last_byte = found.end();
+        }
+    }
+    matches
+}
+
+/// The matches on `line_index`, out of all matches in document order.
+pub fn line_matches(matches: &[Match], line_index: usize) -> &[Match] {
+    let start = matches.partition_point(|found| found.line < line_index);
+    let end = matches.partition_point(|found| found.line <= line_index);
+    &matches[start..end]
+}
+
+/// Index of the first match after `from` (or at it, with `inclusive`), or
+/// before it when searching backwards, wrapping around the document.
+pub fn next_match(
+    matches: &[Match],
+    from: Cursor,
+    forward: bool,
+    inclusive: bool,
+) -> Option<usize> {
+    if matches.is_empty() {
+        return None;
+    }
+    if forward {
+        let index = matches.partition_point(|found| {
+            if inclusive {
+                found.cursor() < from
+            } else {
+                found.cursor() <= from
+            }
+        });
+        Some(index % matches.len())
+    } else {
+        let index = matches.partition_point(|found| found.cursor() < from);
+        Some(index.checked_sub(1).unwrap_or(matches.len() - 1))
+    }
+}
@@ -0,0 +1,199 @@
+use crate::{
+    buffer::Buffer,
+    range::{self, Context, LineRange},
+    search::{self, CaseMode, SearchOptions},
+};
+use regex::Regex;
+use std::ops::{Range, RangeInclusive};
+
+/// A parsed `[range]s/pattern/replacement/[flags]` command.
+pub struct Substitute {
+    /// The cursor line when no range is given.
+    range: Option<LineRange>,
+    regex: Regex,
+    replacement: String,
+    global: bool,
+    pub confirm: bool,
+}
+
+/// One match to replace, as an absolute char range and its replacement.
+struct Replacement {
+    range: Range<usize>,
+    text: String,
+}
+
+/// Walks the replacements in document order, one answer at a time, for
+/// both confirmed and unconfirmed substitutions.
+pub struct Confirm {
+    replacements: Vec<Replacement>,
+    index: usize,
+    /// Chars added (or removed, if negative) by the replacements made so far.
+    delta: isize,
+    pub count: usize,
+}
+
+/// Parses a substitute command. The pattern is always a regular expression;
+/// its case matching follows the search options unless the `i` (ignore
+/// case) or `I` (match case) flag is given. `g` replaces every match on a
+/// line instead of the first one, and `c` asks before each replacement.
+pub fn parse(command: &str, options: SearchOptions) -> Result<Substitute, String> {
+    let (range, rest) = range::parse(command)?;
+    let Some(rest) = rest.strip_prefix('s') else {
+        return Err("expected s/pattern/replacement/".to_string());
+    };
+    let mut rest = rest.chars();
+    let delimiter = match rest.next() {
+        Some(c) if !c.is_alphanumeric() && c != '\\' && !c.is_whitespace() => c,
+        _ => return Err("expected a delimiter after s".to_string()),
+    };
+    let fields = split_fields(rest.as_str(), delimiter);
+    let (pattern, replacement, flags) = match &fields[..] {
+        [pattern] => (pattern.as_str(), "", ""),
+        [pattern, replacement] => (pattern.as_str(), replacement.as_str(), ""),
+        [pattern, replacement, flags] => (pattern.as_str(), replacement.as_str(), flags.as_str()),
+        _ => return Err("too many delimiters".to_string()),
+    };
+    if pattern.is_empty() {
+        return Err("empty pattern".to_string());
+    }
+
+    let mut options = SearchOptions {
+        regex: true,
+        ..options
+    };
+    let mut global = false;
+    let mut confirm = false;
+    for flag in flags.chars() {
+        match flag {
+            'g' => global = true,
+            'c' => confirm = true,
+            'i' => options.case = CaseMode::Insensitive,
+            'I' => options.case = CaseMode::Sensitive,
+            _ => return Err(format!("unknown flag `{flag}`")),
+        }
+    }
+    let regex = search::compile(pattern, options).map_err(|_| "invalid pattern".to_string())?;
+    Ok(Substitute {
+        range,
+        regex,
+        replacement: expand_replacement(replacement),
+        global,
+        confirm,
+    })
+}
+
+/// Splits on unescaped `delimiter`s, unescaping `\<delimiter>`.
+fn split_fields(text: &str, delimiter: char) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let field = fields.last_mut().unwrap();
+        if c == '\\' {
+            match chars.next() {
+                Some(next) if next == delimiter => field.push(next),
+                Some(next) => {
+                    field.push('\\');
+                    field.push(next);
+                }
+                None => field.push('\\'),
+            }
+        } else if c == delimiter {
+            fields.push(String::new());
+        } else {
+            field.push(c);
+        }
+    }
+    fields
+}
+
+/// Turns a sed-style replacement into the `regex` crate's syntax: `&` and
+/// `\0` are the whole match, `\1`-`\9` are capture groups, `$1` and `${name}`
+/// work as they do in `regex`, and `\n` is a line break.
+fn expand_replacement(replacement: &str) -> String {
+    let mut out = String::new();
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => out.push_str("${0}"),
+            '\\' => match chars.next() {
+                Some(digit @ '0'..='9') => out.push_str(&format!("${{{digit}}}")),
+                Some('n') => out.push('\n'),
+                Some('$') => out.push_str("$$"),
+                Some(other) => out.push(other),
+                None => out.push('\\'),
+            },
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+impl Substitute {
+    pub fn lines(&self, context: &Context) -> Result<RangeInclusive<usize>, String> {
+        match &self.range {
+            Some(range) => range.resolve(context),
+            None => Ok(context.cursor_line..=context.cursor_line),
+        }
+    }
+
+    /// Finds the matches in `lines` and expands their replacements.
+    pub fn replacements(&self, buffer: &Buffer, lines: RangeInclusive<usize>) -> Confirm {
+        let mut replacements = Vec::new();
+        for line_index in lines {
+            let line = buffer.line_string(line_index);
+            let line_start = buffer.line_to_char(line_index);
+            for captures in self.regex.captures_iter(&line) {
+                let found = captures.get(0).unwrap();
+                let start = line_start + line[..found.start()].chars().count();
+                let mut text = String::new();
+                captures.expand(&self.replacement, &mut text);
+                replacements.push(Replacement {
+                    range: start..start + found.as_str().chars().count(),
+                    text,
+                });
+                if !self.global {
+                    break;
+                }
+            }
+        }
+        Confirm {
+            replacements,
+            index: 0,
+            delta: 0,
+            count: 0,
+        }
+    }
+}
+
+impl Confirm {
+    pub fn is_empty(&self) -> bool {
+        self.replacements.is_empty()
+    }
+
+    /// The match to answer for next, shifted by the replacements made
+    /// before it, with its replacement text.
+    pub fn current(&self) -> Option<(Range<usize>, String)> {
+        let replacement = self.replacements.get(self.index)?;
+        let start = replacement.range.start.checked_add_signed(self.delta)?;
+        let end = replacement.range.end.checked_add_signed(self.delta)?;
+        Some((start..end, replacement.text.clone()))
+    }
+
+    /// Moves past the current match, after it was replaced or skipped.
+    pub fn advance(&mut self, replaced: bool) {
+        if let Some(replacement) = self.replacements.get(self.index) {
+            if replaced {
+                let removed = replacement.range.len() as isize;
+                let added = replacement.text.chars().count() as isize;
+                self.delta += added - removed;
+                self.count += 1;
+            }
+            self.index += 1;
+        }
+    }
+
+    /// Skips the remaining matches.
+    pub fn stop(&mut self) {
+        self.index = self.replacements.len();
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{buffer::Buffer, history::Cursor, save, undofile};
+use std::{
+    fs::{self, read_to_string},
+    io::{self, Write},
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::{Mutex, PoisonError},
+    time::SystemTime,
+};
+
+const MAGIC: &str = "edfi-swap 1";
+
+/// Unsaved buffer contents and the cursor, as last written to the
+/// recovery file.
+pub struct Swap {
+    pub text: String,
+    pub cursor: Cursor,
+}
+
+/// Unsaved changes as of the last key press, for the panic hook, which
+/// cannot reach the editor's own state.
+struct Tracked {
+    file_path: String,
+    buffer: Buffer,
+    cursor: Cursor,
+}
+
+static TRACKED: Mutex<Option<Tracked>> = Mutex::new(None);
+
+/// What to do about a recovery file found on open.
+pub enum Recovery {
+    Recover(Swap),
+    /// Edit the file as it is on disk.
+    Ignore,
+    Quit,
+}
+
+/// `$XDG_STATE_HOME/edfi/swap/<name>.<path hash>`, next to the undo files.
+fn swap_file_path(file_path: &str) -> Option<PathBuf> {
+    undofile::state_file_path("swap", file_path)
+}
+
+pub fn load(file_path: &str) -> Option<Swap> {
+    let text = read_to_string(swap_file_path(file_path)?).ok()?;
+    let (magic, rest) = text.split_once('\n')?;
+    let (cursor, text) = rest.split_once('\n')?;
+    let (line, column) = cursor.split_once(' ')?;
+    if magic != MAGIC {
+        return None;
+    }
+    Some(Swap {
+        text: text.to_string(),
+        cursor: (line.parse().ok()?, column.parse().ok()?),
+    })
+}
+
+/// Writes the recovery file for `file_path`, returning where it went.
+pub fn save(file_path: &str, buffer: &Buffer, cursor: Cursor) -> io::Result<PathBuf> {
+    let path = swap_file_path(file_path)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no state directory"))?;
+    undofile::create_state_dir(&path)?;
+    save::write_private(&path, |file| {
+        write!(file, "{MAGIC}\n{} {}\n", cursor.0, cursor.1)?;
+        buffer.write_to(file)
+    })?;
+    Ok(path)
+}
+
+/// Keeps the buffer for `save_tracked`, or forgets it when there is
+/// nothing unsaved. Cloning a buffer is cheap, as the text is shared.
+pub fn track(unsaved: Option<(&str, &Buffer, Cursor)>) {
+    let tracked = unsaved.map(|(file_path, buffer, cursor)| Tracked {
+        file_path: file_path.to_string(),
+        buffer: buffer.clone(),
+        cursor,
+    });
+    *TRACKED.lock().unwrap_or_else(PoisonError::into_inner) = tracked;
+}
+
+/// Writes the tracked buffer to its recovery file, if there is one.
+pub fn save_tracked() -> Option<PathBuf> {
+    let tracked = TRACKED.lock().unwrap_or_else(PoisonError::into_inner);
+    let tracked = tracked.as_ref()?;
+    save(&tracked.file_path, &tracked.buffer, tracked.cursor).ok()
+}
+
+pub fn remove(file_path: &str) {
+    if let Some(path) = swap_file_path(file_path) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Looks for a recovery file left behind for `file_path` by a session
+/// that did not exit cleanly, and asks on the terminal what to do with it.
+/// A recovery file with the same text as the file on disk holds nothing
+/// to recover and is removed without asking.
+pub fn prompt(file_path: &str, file_text: &str) -> io::Result<Recovery> {
+    let Some(swap) = load(file_path) else {
+        return Ok(Recovery::Ignore);
+    };
+    if swap.text == file_text {
+        remove(file_path);
+        return Ok(Recovery::Ignore);
+    }
+    let age = swap_file_path(file_path)
+        .and_then(|path| fs::metadata(path).ok()?.modified().ok())
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .map(|age| match age.as_secs() {
+            secs if secs < 60 => format!(", written {secs}s ago"),
+            secs if secs < 3600 => format!(", written {}m ago", secs / 60),
+            secs if secs < 86400 => format!(", written {}h ago", secs / 3600),
+            secs => format!(", written {}d ago", secs / 86400),
+        })
+        .unwrap_or_default();
+    println!("Found unsaved changes to {file_path} from an earlier session{age}.");
+    loop {
+        print!("[r]ecover them, show a [d]iff, de[l]ete them, [e]dit the file as saved, [q]uit: ");
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer)? == 0 {
+            return Ok(Recovery::Ignore);
+        }
+        match answer.trim() {
+            "r" => return Ok(Recovery::Recover(swap)),
+            "d" => diff(file_path, &swap.text)?,
+            "l" => {
+                remove(file_path);
+                return Ok(Recovery::Ignore);
+            }
+            "e" => return Ok(Recovery::Ignore),
+            "q" => return Ok(Recovery::Quit),
+            _ => {}
+        }
+    }
+}
+
+/// Prints `diff -u` from the saved file to the recovered text.
+fn diff(file_path: &str, text: &str) -> io::Result<()> {
+    let mut child = Command::new("diff")
+        .args([
+            "-u",
+            "--label",
+            file_path,
+            "--label",
+            "recovered",
+            "--",
+            file_path,
+            "-",
+        ])
+        .stdin(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(text.as_bytes());
+    }
+    child.wait()?;
+    Ok(())
+}
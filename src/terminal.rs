@@ -0,0 +1,55 @@
+use crate::swap;
+use ratatui::{
+    backend::CrosstermBackend,
+    crossterm::{
+        event::{DisableBracketedPaste, EnableBracketedPaste},
+        execute,
+        terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    },
+    DefaultTerminal, Terminal,
+};
+use std::{io, panic};
+
+/// Keeps the terminal in raw mode on the alternate screen, and puts it
+/// back when dropped, however `main` returns.
+pub struct TerminalGuard;
+
+impl TerminalGuard {
+    /// Sets up the terminal and a panic hook that restores it. A panic
+    /// aborts without unwinding in release builds, so the hook is the only
+    /// chance to clean up: it also writes an emergency copy of unsaved
+    /// changes before the report is printed.
+    pub fn init() -> io::Result<(Self, DefaultTerminal)> {
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            restore();
+            if let Some(path) = swap::save_tracked() {
+                eprintln!(
+                    "Unsaved changes were saved to {}, open the file again to recover them.",
+                    path.display()
+                );
+            }
+            hook(info);
+        }));
+        // The guard exists before the first change, so a failure halfway
+        // still restores what was set up.
+        let guard = Self;
+        enable_raw_mode()?;
+        execute!(io::stdout(), EnterAlternateScreen, EnableBracketedPaste)?;
+        let terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+        Ok((guard, terminal))
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        restore();
+    }
+}
+
+/// Leaves raw mode and the alternate screen. Errors are ignored, as this
+/// runs while exiting anyway.
+fn restore() {
+    let _ = disable_raw_mode();
+    let _ = execute!(io::stdout(), DisableBracketedPaste, LeaveAlternateScreen);
+}
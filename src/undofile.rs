@@ -0,0 +1,81 @@
+use crate::{buffer::Buffer, history::History, save};
+use std::{
+    env,
+    fs::{self, read_to_string},
+    io::{self, Write},
+    path::{self, Path, PathBuf},
+};
+
+const MAGIC: &str = "edfi-undo 2";
+
+/// FNV-1a, used both to name undo files and to check that an undo file
+/// still matches the text it was written for.
+fn fnv1a<'a>(chunks: impl Iterator<Item = &'a [u8]>) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for chunk in chunks {
+        for byte in chunk {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+pub fn content_hash(buffer: &Buffer) -> u64 {
+    fnv1a(buffer.chunks().map(str::as_bytes))
+}
+
+/// `$XDG_STATE_HOME/edfi/<kind>/<name>.<path hash>`, falling back to
+/// `~/.local/state` when `XDG_STATE_HOME` is unset.
+pub fn state_file_path(kind: &str, file_path: &str) -> Option<PathBuf> {
+    let state_dir = match env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".local/state"),
+    };
+    let absolute = path::absolute(file_path).ok()?;
+    let name = absolute.file_name()?.to_string_lossy();
+    let path_hash = fnv1a([absolute.as_os_str().as_encoded_bytes()].into_iter());
+    Some(state_dir.join(format!("edfi/{kind}/{name}.{path_hash:016x}")))
+}
+
+/// Creates the directory of the state file at `path`, only accessible to
+/// its owner, as the files in it hold unsaved text.
+pub fn create_state_dir(path: &Path) -> io::Result<()> {
+    let Some(dir) = path.parent() else {
+        return Ok(());
+    };
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+        builder.mode(0o700).create(dir)?;
+        // Directories made before they were private keep their mode
+        // otherwise.
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))
+    }
+    #[cfg(not(unix))]
+    builder.create(dir)
+}
+
+/// Reads the undo history for `file_path` if it was written for the same
+/// contents as `buffer`.
+pub fn load(file_path: &str, buffer: &Buffer) -> Option<History> {
+    let text = read_to_string(state_file_path("undo", file_path)?).ok()?;
+    let (magic, rest) = text.split_once('\n')?;
+    let (hash, rest) = rest.split_once('\n')?;
+    if magic != MAGIC || u64::from_str_radix(hash, 16).ok()? != content_hash(buffer) {
+        return None;
+    }
+    History::decode(rest)
+}
+
+pub fn save(file_path: &str, buffer: &Buffer, history: &History) -> io::Result<()> {
+    let path = state_file_path("undo", file_path)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no state directory"))?;
+    create_state_dir(&path)?;
+    let hash = content_hash(buffer);
+    save::write_private(&path, |file| {
+        write!(file, "{MAGIC}\n{hash:016x}\n{}", history.encode())
+    })
+}